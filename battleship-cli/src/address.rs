@@ -0,0 +1,101 @@
+// Copyright 2020 Remi Bernotavicius
+
+//! Where to find the server, and how to talk to it there.
+
+use std::io;
+use std::{net, str};
+use websocket::{ClientBuilder, Message, OwnedMessage};
+
+/// A connection to the server over any of the supported transports.
+pub trait Stream: io::Read + io::Write {}
+
+impl<S: io::Read + io::Write> Stream for S {}
+
+/// The address of a server, as `tcp://host:port`, `unix:///path/to/socket` or `ws://host:port/ws`.
+/// Without a scheme it is taken to be TCP.
+#[derive(Debug, Clone)]
+pub enum ServerAddress {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+    WebSocket(String),
+}
+
+impl str::FromStr for ServerAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.split_once("://") {
+            None => Ok(Self::Tcp(s.into())),
+            Some(("tcp", address)) => Ok(Self::Tcp(address.into())),
+            #[cfg(unix)]
+            Some(("unix", path)) => Ok(Self::Unix(path.into())),
+            Some(("ws", _)) => Ok(Self::WebSocket(s.into())),
+            Some((scheme, _)) => Err(format!("unsupported scheme {}", scheme)),
+        }
+    }
+}
+
+impl ServerAddress {
+    pub fn connect(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(match self {
+            Self::Tcp(address) => Box::new(net::TcpStream::connect(address)?),
+            #[cfg(unix)]
+            Self::Unix(path) => Box::new(std::os::unix::net::UnixStream::connect(path)?),
+            Self::WebSocket(url) => Box::new(WsStream::connect(url)?),
+        })
+    }
+}
+
+fn other<E: ToString>(e: E) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+/// Carries the stream in binary websocket messages, like the browser client does.
+struct WsStream {
+    client: websocket::sync::Client<net::TcpStream>,
+    /// Received, but not read yet.
+    buffer: Vec<u8>,
+}
+
+impl WsStream {
+    fn connect(url: &str) -> io::Result<Self> {
+        let client = ClientBuilder::new(url)
+            .map_err(other)?
+            .connect_insecure()
+            .map_err(other)?;
+        Ok(Self {
+            client,
+            buffer: vec![],
+        })
+    }
+}
+
+impl io::Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.buffer.is_empty() {
+            match self.client.recv_message().map_err(other)? {
+                OwnedMessage::Binary(data) => self.buffer = data,
+                OwnedMessage::Text(text) => self.buffer = text.into_bytes(),
+                OwnedMessage::Close(_) => return Ok(0),
+                _ => {}
+            }
+        }
+        let read = self.buffer.as_slice().read(buf)?;
+        self.buffer.drain(..read);
+        Ok(read)
+    }
+}
+
+impl io::Write for WsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.client
+            .send_message(&Message::binary(buf))
+            .map_err(other)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.client.writer_mut().flush()
+    }
+}
@@ -1,14 +1,21 @@
 // Copyright 2020 Remi Bernotavicius
 
+use address::{ServerAddress, Stream};
 use battleship_game::{
-    client::blocking::BlockingGameClient, row_to_letter, server::blocking::BlockingGameServer,
-    BattleField, Cell, Direction, Game, GameId, Location, Play, Player, PlayerId, Ship, ShipId,
+    client::blocking::{BlockingGameClient, Error as ClientError},
+    protocol::GameEvent,
+    row_to_letter,
+    server::{blocking::BlockingGameServer, storage::FileStorage, GameServer},
+    BattleField, Cell, Direction, Game, GameId, GameOptions, JoinCode, Location, Play, Player,
+    PlayerId, Ship, ShipId,
 };
 use log::info;
 use std::collections::HashMap;
 use std::io::{self, BufRead as _, Write as _};
 use std::{fmt, net, str};
 
+mod address;
+
 #[derive(Debug)]
 enum Error {
     Io(io::Error),
@@ -43,6 +50,8 @@ impl From<battleship_game::server::blocking::Error> for Error {
     }
 }
 
+type Client = BlockingGameClient<Box<dyn Stream>>;
+
 fn format_battlefield(ships: &HashMap<ShipId, Ship>, field: &BattleField) -> Vec<String> {
     let mut lines = vec![];
 
@@ -178,82 +187,382 @@ fn local_game() -> Result<()> {
     Ok(())
 }
 
-fn server() -> Result<()> {
-    simple_logger::init().unwrap();
+/// Where the server listens for players.
+enum Listen {
+    Tcp,
+    /// A socket file, which only those with `mode` permission on it can connect to.
+    #[cfg(unix)]
+    Unix {
+        path: String,
+        mode: u32,
+    },
+}
+
+/// Parse `server [--unix PATH [--mode MODE]] [DATA_DIRECTORY]`. MODE is octal, and defaults to
+/// letting only the owner connect.
+fn parse_server_args<'a>(mut args: impl Iterator<Item = &'a str>) -> (Listen, Option<&'a str>) {
+    let mut listen = Listen::Tcp;
+    let mut data_directory = None;
+    while let Some(arg) = args.next() {
+        match arg {
+            #[cfg(unix)]
+            "--unix" => {
+                let path = args.next().expect("--unix needs a path").into();
+                listen = Listen::Unix { path, mode: 0o600 };
+            }
+            #[cfg(unix)]
+            "--mode" => match &mut listen {
+                Listen::Unix { mode, .. } => {
+                    let arg = args.next().expect("--mode needs a mode");
+                    *mode = u32::from_str_radix(arg, 8).expect("mode must be octal");
+                }
+                Listen::Tcp => panic!("--mode only applies to --unix"),
+            },
+            directory => data_directory = Some(directory),
+        }
+    }
+    (listen, data_directory)
+}
 
-    let listener = net::TcpListener::bind("0.0.0.0:0")?;
-    info!("listening on {}", listener.local_addr()?);
+/// Bind a socket at `path` that only those with `mode` permission can connect to. A socket left
+/// behind by a server that is no longer running is replaced.
+#[cfg(unix)]
+fn bind_unix(path: &str, mode: u32) -> io::Result<std::os::unix::net::UnixListener> {
+    use std::fs;
+    use std::os::unix::fs::{DirBuilderExt as _, FileTypeExt as _, PermissionsExt as _};
+    use std::os::unix::net;
+    use std::path::Path;
+
+    let stale = match fs::metadata(path) {
+        Ok(metadata) => metadata.file_type().is_socket() && net::UnixStream::connect(path).is_err(),
+        Err(_) => false,
+    };
+    if stale {
+        fs::remove_file(path)?;
+    }
+
+    // The socket is created in a directory only we can get into, and linked into place once it
+    // has its permissions, so nobody can connect to it in between.
+    let path = Path::new(path);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let private = path.with_file_name(format!(".{}.{}", name, std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bind = || {
+        let socket = private.join("socket");
+        let listener = net::UnixListener::bind(&socket)?;
+        fs::set_permissions(&socket, fs::Permissions::from_mode(mode))?;
+        fs::hard_link(&socket, path)?;
+        Ok(listener)
+    };
+    let listener = bind();
+    fs::remove_dir_all(&private).ok();
+    listener
+}
+
+/// Serve games, keeping them in `data_directory` if given so they survive a restart.
+fn server(listen: Listen, data_directory: Option<&str>) -> Result<()> {
+    simple_logger::init().unwrap();
 
-    let mut game_server = BlockingGameServer::new();
-    game_server.run(&listener);
+    let game = match data_directory {
+        Some(directory) => GameServer::with_storage(Box::new(FileStorage::open(directory)?))?,
+        None => GameServer::new(),
+    };
+    let mut game_server = BlockingGameServer::with_game_server(game);
+
+    match listen {
+        Listen::Tcp => {
+            let listener = net::TcpListener::bind("0.0.0.0:0")?;
+            info!("listening on {}", listener.local_addr()?);
+            game_server.run(&listener);
+        }
+        #[cfg(unix)]
+        Listen::Unix { path, mode } => {
+            let listener = bind_unix(&path, mode)?;
+            info!("listening on unix://{} with mode {:o}", path, mode);
+            game_server.run(&listener);
+        }
+    }
     Ok(())
 }
 
-fn client(address: &str, game_id: Option<GameId>) -> Result<()> {
-    let conn = net::TcpStream::connect(address)?;
+/// Follow the game's events until it is our turn. Returns the winner instead if the game ends.
+fn wait_for_turn(game: &mut Client) -> Result<Option<PlayerId>> {
+    let player_id = game.player_id();
+    println!("waiting for other player");
+    loop {
+        match game.next_event()? {
+            GameEvent::OpponentJoined(_, name) => println!("{} joined the game", name),
+            GameEvent::OpponentReady(_) => println!("other player placed their ships"),
+            GameEvent::FirstTurn { player, chosen_by } if player == player_id => {
+                println!("you shoot first, {}", chosen_by)
+            }
+            GameEvent::FirstTurn { chosen_by, .. } => {
+                println!("other player shoots first, {}", chosen_by)
+            }
+            GameEvent::ShotFired { target, result, .. } if target == player_id => {
+                println!("{}", result)
+            }
+            GameEvent::OpponentDisconnected(_) => {
+                println!("other player disconnected, waiting for them to reconnect")
+            }
+            GameEvent::Chat { name, message, .. } => println!("{}: {}", name, message),
+            GameEvent::Turn(id) if id == player_id => return Ok(None),
+            GameEvent::GameOver { winner } => return Ok(Some(winner)),
+            _ => {}
+        }
+    }
+}
 
-    let name: String = ask("name: ")?;
-    let mut game = BlockingGameClient::new(conn, &name, game_id)?;
+enum LobbyChoice {
+    Join(GameId),
+    JoinWithCode(JoinCode),
+    New(GameOptions),
+    QuickMatch,
+    Refresh,
+}
 
-    println!("joined game {}", game.game_id());
+impl str::FromStr for LobbyChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let new_game = |private, best_of: &str| -> std::result::Result<Self, String> {
+            let best_of = match best_of {
+                "" => 1,
+                n => n
+                    .parse()
+                    .map_err(|_| format!("invalid series length {}", n))?,
+            };
+            Ok(Self::New(GameOptions {
+                best_of,
+                private,
+                ..GameOptions::default()
+            }))
+        };
+        let s = s.trim();
+        // A join code made of digits is told apart from a game id by the dash it is shown with.
+        let digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+        if !s.is_empty() && digits(s) {
+            return s
+                .parse()
+                .map(Self::Join)
+                .map_err(|_| format!("invalid game {}", s));
+        }
+        // Join codes can start with an n or a p too, so they come before the new game choices.
+        if let Ok(join_code) = s.parse() {
+            return Ok(Self::JoinWithCode(join_code));
+        }
+        match s {
+            s if s.starts_with('n') && digits(&s[1..]) => new_game(false, &s[1..]),
+            s if s.starts_with('p') && digits(&s[1..]) => new_game(true, &s[1..]),
+            "q" => Ok(Self::QuickMatch),
+            "" | "r" => Ok(Self::Refresh),
+            s => s
+                .parse()
+                .map(Self::Join)
+                .map_err(|_| format!("invalid choice {}", s)),
+        }
+    }
+}
 
-    let player_id = game.player_id();
-    place_ships(&mut game, player_id)?;
+/// Join or create a game as the player chose. Returns false if they only asked to see the lobby.
+fn enter_game(
+    game: &mut Client,
+    choice: LobbyChoice,
+    name: &str,
+) -> std::result::Result<bool, ClientError> {
+    match choice {
+        LobbyChoice::Join(game_id) => game.join_game(game_id, name)?,
+        LobbyChoice::JoinWithCode(join_code) => game.join_with_code(join_code, name)?,
+        LobbyChoice::New(options) => game.create_custom_game(name, options)?,
+        LobbyChoice::QuickMatch => game.quick_match(name)?,
+        LobbyChoice::Refresh => return Ok(false),
+    }
+    Ok(true)
+}
 
-    print_battlefield(game.get_player(player_id).unwrap());
+/// List the games waiting for an opponent until the player picks one, or starts their own.
+fn lobby(game: &mut Client, name: &str) -> Result<()> {
+    loop {
+        let open_games = game.list_open_games()?;
+        if open_games.is_empty() {
+            println!("no open games");
+        }
+        for open_game in &open_games {
+            println!(
+                "{}: {} ({})",
+                open_game.game_id, open_game.host, open_game.rules
+            );
+        }
 
-    println!("waiting for other player");
-    if let Some(result) = game.wait_for_turn()? {
-        println!("{}", result);
+        println!("add 3, 5 or 7 to a new game to play a best of series, like n3 or p5");
+        let choice =
+            ask("game or join code, (n)ew game, new (p)rivate game, (q)uick match or (r)efresh: ")?;
+        match enter_game(game, choice, name) {
+            Ok(true) => break Ok(()),
+            Ok(false) => {}
+            Err(ClientError::Game(e)) => println!("error: {}", e),
+            Err(e) => break Err(e.into()),
+        }
     }
+}
 
-    print_battlefield(game.get_player(player_id).unwrap());
+/// Play a game on the server at `address`. If the connection is lost part way through, the client
+/// reconnects and carries on with the same player.
+fn client(address: ServerAddress, choice: Option<LobbyChoice>) -> Result<()> {
+    simple_logger::init_with_level(log::Level::Warn).unwrap();
+    let mut game = Client::connect_with(move || address.connect())?;
 
-    let other_player_id = game.other_player_ids()[0];
+    let name: String = ask("name: ")?;
+    let entered = match choice {
+        Some(choice) => enter_game(&mut game, choice, &name)?,
+        None => false,
+    };
+    if !entered {
+        lobby(&mut game, &name)?;
+    }
 
-    let mut winner = None;
-    while winner.is_none() {
-        do_attack(&mut game, player_id, other_player_id)?;
+    println!("joined game {}", game.game_id());
+    if let Some(join_code) = game.join_code() {
+        println!("others can join with the code {}", join_code);
+    }
 
-        winner = game.winner()?;
-        if winner.is_some() {
-            break;
+    loop {
+        play(&mut game)?;
+        let again: String = ask("rematch? (y/n): ")?;
+        if again.trim() != "y" {
+            break Ok(());
         }
+        game.rematch()?;
+        wait_for_rematch(&mut game)?;
+    }
+}
 
-        print_battlefield(game.get_player(player_id).unwrap());
+/// Play the game the client is in until someone wins.
+fn play(game: &mut Client) -> Result<()> {
+    let player_id = game.player_id();
+    place_ships(game, player_id)?;
 
-        println!("waiting for other player");
-        if let Some(result) = game.wait_for_turn()? {
-            println!("{}", result);
-        }
+    print_battlefield(game.get_player(player_id).unwrap());
 
+    let winner = loop {
+        if let Some(winner) = wait_for_turn(game)? {
+            break winner;
+        }
         print_battlefield(game.get_player(player_id).unwrap());
 
-        winner = game.winner()?;
-    }
+        let other_player_id = game.other_player_ids()[0];
+        do_attack(game, player_id, other_player_id)?;
 
-    if winner.unwrap() == player_id {
+        print_battlefield(game.get_player(player_id).unwrap());
+    };
+
+    if winner == player_id {
         println!("you win");
     } else {
         println!("you lose");
     }
+    if let Some(series) = game.series() {
+        let (ours, theirs) = series.score(player_id);
+        println!("series: {} to {}, best of {}", ours, theirs, series.best_of);
+    }
     Ok(())
 }
 
+/// Wait for the other player to accept our rematch, after which we are in the new game.
+fn wait_for_rematch(game: &mut Client) -> Result<()> {
+    println!("waiting for other player to accept the rematch");
+    loop {
+        match game.next_event()? {
+            GameEvent::Chat { name, message, .. } => println!("{}: {}", name, message),
+            GameEvent::OpponentDisconnected(_) => println!("other player disconnected"),
+            GameEvent::Rematch { game_id, .. } => {
+                println!("joined game {}", game_id);
+                break Ok(());
+            }
+            _ => {}
+        }
+    }
+}
+
 fn main() -> Result<()> {
     let args: Vec<_> = std::env::args().collect();
     let mut iter = args.iter().skip(1).map(|s| s.as_ref());
 
     match iter.next() {
         None => local_game()?,
-        Some("server") => server()?,
+        Some("server") => {
+            let (listen, data_directory) = parse_server_args(iter);
+            server(listen, data_directory)?;
+        }
         Some("client") => {
-            let address = iter.next().unwrap();
-            let game_id = iter.next().map(|s| s.parse().unwrap());
-            client(address, game_id)?;
+            let address = iter.next().unwrap().parse().unwrap();
+            let choice = iter.next().map(|s| s.parse().unwrap());
+            client(address, choice)?;
         }
         Some(s) => println!("invalid command {}", s),
     }
 
     Ok(())
 }
+
+#[test]
+fn test_lobby_choice_join_code_starting_with_n_or_p() {
+    let parse = |s: &str| s.parse::<LobbyChoice>().unwrap();
+    for code in ["N23ABC", "nabcde", "P2Q-RST"] {
+        assert!(matches!(
+            parse(code),
+            LobbyChoice::JoinWithCode(join_code) if join_code == code.parse().unwrap()
+        ));
+    }
+    assert!(matches!(
+        parse("n"),
+        LobbyChoice::New(GameOptions {
+            best_of: 1,
+            private: false,
+            ..
+        })
+    ));
+    assert!(matches!(
+        parse("p3"),
+        LobbyChoice::New(GameOptions {
+            best_of: 3,
+            private: true,
+            ..
+        })
+    ));
+    assert!(matches!(parse("12"), LobbyChoice::Join(game_id) if game_id.to_string() == "12"));
+    assert!("nope".parse::<LobbyChoice>().is_err());
+}
+
+#[test]
+fn test_lobby_choice_digits_are_a_game_id() {
+    let parse = |s: &str| s.parse::<LobbyChoice>().unwrap();
+    assert!(matches!(
+        parse("234567"),
+        LobbyChoice::Join(game_id) if game_id.to_string() == "234567"
+    ));
+    assert!(matches!(
+        parse("234-567"),
+        LobbyChoice::JoinWithCode(join_code) if join_code == "234567".parse().unwrap()
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_bind_unix_sets_permissions() {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let directory = std::env::temp_dir().join(format!("battleship-cli-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("battleship.sock");
+    let path = path.to_str().unwrap();
+    let listener = bind_unix(path, 0o600).unwrap();
+    let mode = std::fs::metadata(path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    std::os::unix::net::UnixStream::connect(path).unwrap();
+    listener.accept().unwrap();
+
+    // Only the socket is left behind.
+    assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+    std::fs::remove_dir_all(&directory).unwrap();
+}
@@ -1,33 +1,52 @@
 // copyright 2020 Remi Bernotavicius
 
 use super::{ClientResponse, GameClient};
-use crate::protocol::Response;
+use crate::protocol::{
+    ClientMessage, GameEvent, GameState, OpenGame, RequestId, SeriesScore, ServerMessage,
+};
+use crate::transport::{self, FramedStream};
 use crate::{
-    AttackResult, Direction, Error as GameError, GameId, Location, Play, Player, PlayerId,
-    Result as GameResult, ShipId,
+    AttackResult, Cell, Direction, Error as GameError, GameId, GameOptions, JoinCode, Location,
+    Play, Player, PlayerId, Result as GameResult, ShipId,
 };
-use serde::Deserialize;
+use log::warn;
+use std::collections::VecDeque;
 use std::io;
 use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait before reconnecting, doubling after each failed attempt up to
+/// `MAX_RECONNECT_DELAY`.
+const FIRST_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+const RECONNECT_ATTEMPTS: u32 = 10;
 
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
-    Serde(serde_json::Error),
+    Transport(transport::Error),
     Game(crate::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl Error {
+    /// Whether the connection was lost, as opposed to the server refusing something.
+    fn is_disconnect(&self) -> bool {
+        matches!(self, Self::Io(_) | Self::Transport(transport::Error::Io(_)))
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
         Self::Io(e)
     }
 }
 
-impl From<serde_json::Error> for Error {
-    fn from(e: serde_json::Error) -> Self {
-        Self::Serde(e)
+impl From<transport::Error> for Error {
+    fn from(e: transport::Error) -> Self {
+        Self::Transport(e)
     }
 }
 
@@ -37,55 +56,258 @@ impl From<crate::Error> for Error {
     }
 }
 
-pub struct BlockingGameClient {
+/// Opens a new connection to the server.
+type Open<S> = Box<dyn FnMut() -> io::Result<S> + Send>;
+
+/// A client talking to the server over any stream. If it was given a way to open new connections,
+/// then once it has a player it reconnects and picks up where it left off if the connection is
+/// lost.
+pub struct BlockingGameClient<S = TcpStream> {
     game: GameClient,
-    connection: TcpStream,
+    open: Option<Open<S>>,
+    connection: FramedStream<S>,
+    events: VecDeque<GameEvent>,
 }
 
-impl BlockingGameClient {
-    pub fn new(mut connection: TcpStream, name: &str, game_id: Option<GameId>) -> Result<Self> {
-        let mut game = GameClient::new();
+impl<S: io::Read + io::Write> BlockingGameClient<S> {
+    /// Join the given game as a new player, or create a game if none is given.
+    pub fn new(connection: S, name: &str, game_id: Option<GameId>) -> Result<Self> {
+        let mut client = Self::connect(connection)?;
+        match game_id {
+            Some(game_id) => client.join_game(game_id, name)?,
+            None => client.create_game(name)?,
+        }
+        Ok(client)
+    }
+
+    /// Greet the server, without joining a game yet. The connection isn't replaced if it's lost.
+    pub fn connect(connection: S) -> Result<Self> {
+        Self::greeted(connection, None)
+    }
+
+    /// Greet the server over a connection made by `open`, which is called again to reconnect
+    /// whenever the connection is lost.
+    pub fn connect_with<F>(mut open: F) -> Result<Self>
+    where
+        F: FnMut() -> io::Result<S> + Send + 'static,
+    {
+        let connection = open()?;
+        Self::greeted(connection, Some(Box::new(open)))
+    }
+
+    fn greeted(connection: S, open: Option<Open<S>>) -> Result<Self> {
+        let mut client = Self {
+            game: GameClient::new(),
+            open,
+            connection: FramedStream::new(connection),
+            events: VecDeque::new(),
+        };
+        client.greet()?;
+        Ok(client)
+    }
+
+    fn greet(&mut self) -> Result<()> {
+        let hello = self.game.hello();
+        self.exchange(&hello)?;
+        self.connection.set_codec(self.game.codec());
+        Ok(())
+    }
 
-        if let Some(game_id) = game_id {
-            game.join_game(game_id);
+    /// Connect to the server again after losing the connection with the given error, and take
+    /// back our player. Gives up with that error if we can't open connections, don't have a
+    /// player yet, or the server can't be reached after `RECONNECT_ATTEMPTS`.
+    fn reconnect(&mut self, error: Error) -> Result<()> {
+        if self.open.is_none() || !self.game.can_rejoin() {
+            return Err(error);
+        }
+        let mut delay = FIRST_RECONNECT_DELAY;
+        for _ in 0..RECONNECT_ATTEMPTS {
+            warn!("lost connection to the server, reconnecting in {:?}", delay);
+            thread::sleep(delay);
+            match self.rejoin() {
+                Ok(()) => return Ok(()),
+                Err(e) if e.is_disconnect() => delay = (delay * 2).min(MAX_RECONNECT_DELAY),
+                Err(e) => return Err(e),
+            }
+        }
+        Err(error)
+    }
+
+    /// Greet the server on a new connection, then rejoin and follow our game again. Our view of
+    /// the game is replaced with the server's, and what happened in the meantime arrives as
+    /// events.
+    fn rejoin(&mut self) -> Result<()> {
+        if let Some(open) = &mut self.open {
+            self.connection = FramedStream::new(open()?);
+        }
+        self.greet()?;
+        let rejoin = self.game.rejoin_game(self.game.token()?);
+        self.exchange(&rejoin)?;
+        let get_game_state = self.game.get_game_state()?;
+        self.exchange(&get_game_state)?;
+        let subscribe = self.game.subscribe()?;
+        self.exchange(&subscribe)?;
+        Ok(())
+    }
+
+    pub fn list_open_games(&mut self) -> Result<Vec<OpenGame>> {
+        let request = self.game.list_open_games();
+        if let ClientResponse::OpenGames(open_games) = self.request(request)? {
+            Ok(open_games)
         } else {
-            serde_json::to_writer(&mut connection, &game.create_game())?;
-            let mut de = serde_json::Deserializer::from_reader(&mut connection);
-            game.handle_response(Response::deserialize(&mut de)?)?;
+            Err(Error::Game(GameError::CommunicationError))
         }
+    }
+
+    pub fn create_game(&mut self, name: &str) -> Result<()> {
+        let create_game = self.game.create_game();
+        self.request(create_game)?;
+        let game_id = self.game.game_id();
+        self.join_game(game_id, name)
+    }
 
-        serde_json::to_writer(&mut connection, &game.add_player(name))?;
+    pub fn join_game(&mut self, game_id: GameId, name: &str) -> Result<()> {
+        self.game.join_game(game_id);
+        self.add_player(name)
+    }
 
-        let mut de = serde_json::Deserializer::from_reader(&mut connection);
-        game.handle_response(Response::deserialize(&mut de)?)?;
+    fn add_player(&mut self, name: &str) -> Result<()> {
+        let add_player = self.game.add_player(name)?;
+        self.request(add_player)?;
+        self.subscribe()
+    }
+
+    /// Create a game only those given its code can join.
+    pub fn create_private_game(&mut self, name: &str) -> Result<()> {
+        let create_game = self.game.create_private_game();
+        self.request(create_game)?;
+        self.add_player(name)
+    }
 
-        Ok(Self { game, connection })
+    /// Create a game with the given options, keeping hold of its join code if it is private.
+    pub fn create_custom_game(&mut self, name: &str, options: GameOptions) -> Result<()> {
+        let create_game = self.game.create_custom_game(options);
+        self.request(create_game)?;
+        self.add_player(name)
     }
 
-    pub fn wait_for_turn(&mut self) -> Result<Option<AttackResult>> {
-        let request = self.game.wait_for_turn();
-        serde_json::to_writer(&mut self.connection, &request)?;
+    pub fn join_with_code(&mut self, join_code: JoinCode, name: &str) -> Result<()> {
+        self.game.join_with_code(join_code);
+        self.add_player(name)
+    }
+
+    pub fn quick_match(&mut self, name: &str) -> Result<()> {
+        let quick_match = self.game.quick_match(name);
+        self.request(quick_match)?;
+        self.subscribe()
+    }
+
+    fn subscribe(&mut self) -> Result<()> {
+        let subscribe = self.game.subscribe()?;
+        self.request(subscribe)?;
+        Ok(())
+    }
+
+    /// Receive one message from the server, holding on to any events it contains.
+    fn receive(&mut self) -> Result<(Option<RequestId>, ClientResponse)> {
+        let message: ServerMessage = self.connection.receive()?;
+        let id = message.id;
+        let response = self.game.handle_response(message)?;
+        if let ClientResponse::Events(events) = &response {
+            self.events.extend(events.iter().cloned());
+        }
+        Ok((id, response))
+    }
+
+    /// Send the given message and wait for the response to it, sending it again if we had to
+    /// reconnect first. Only for requests which do the same thing however often they are sent.
+    fn request(&mut self, message: ClientMessage) -> Result<ClientResponse> {
+        self.request_unless_applied(message, |_| None)
+    }
+
+    /// Send the given message and wait for the response to it. If we had to reconnect first, the
+    /// server may have carried it out before the connection was lost, so `applied` is asked
+    /// whether the game state we rejoined with shows that. If it does, what it returns stands in
+    /// for the response. Otherwise the message is sent again.
+    fn request_unless_applied<F>(
+        &mut self,
+        message: ClientMessage,
+        applied: F,
+    ) -> Result<ClientResponse>
+    where
+        F: Fn(&GameClient) -> Option<ClientResponse>,
+    {
+        loop {
+            match self.exchange(&message) {
+                Err(e) if e.is_disconnect() => {
+                    self.reconnect(e)?;
+                    if let Some(response) = applied(&self.game) {
+                        break Ok(response);
+                    }
+                }
+                result => break result,
+            }
+        }
+    }
+
+    /// Send the given message and wait for the response to it. Anything else the server sends in
+    /// the meantime is handled along the way.
+    fn exchange(&mut self, message: &ClientMessage) -> Result<ClientResponse> {
+        self.connection.send(message)?;
+        loop {
+            let (id, response) = self.receive()?;
+            if id == Some(message.id) {
+                break match response {
+                    ClientResponse::Rejected(_, e) => Err(Error::Game(e)),
+                    r => Ok(r),
+                };
+            }
+        }
+    }
 
-        let mut de = serde_json::Deserializer::from_reader(&mut self.connection);
-        let response = Response::deserialize(&mut de)?;
-        if let ClientResponse::Attack(result) = self.game.handle_response(response)? {
-            Ok(Some(result))
+    /// Everything the server knows about our game, which our own view is also replaced with.
+    pub fn game_state(&mut self) -> Result<GameState> {
+        let request = self.game.get_game_state()?;
+        if let ClientResponse::GameState(state) = self.request(request)? {
+            Ok(*state)
         } else {
-            Ok(None)
+            Err(Error::Game(GameError::CommunicationError))
+        }
+    }
+
+    /// Block until the server tells us something happened in the game.
+    pub fn next_event(&mut self) -> Result<GameEvent> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+            match self.receive() {
+                Err(e) if e.is_disconnect() => self.reconnect(e)?,
+                result => {
+                    result?;
+                }
+            }
         }
     }
 
+    /// Offer or accept a rematch. It starts with the `GameEvent::Rematch` that follows.
+    pub fn rematch(&mut self) -> Result<()> {
+        let rematch = self.game.rematch()?;
+        self.request(rematch)?;
+        Ok(())
+    }
+
+    pub fn series(&self) -> Option<&SeriesScore> {
+        self.game.series()
+    }
+
     pub fn other_player_ids(&self) -> Vec<PlayerId> {
         self.game.other_player_ids()
     }
 
     pub fn winner(&mut self) -> Result<Option<PlayerId>> {
-        let request = self.game.winner();
-        serde_json::to_writer(&mut self.connection, &request)?;
-
-        let mut de = serde_json::Deserializer::from_reader(&mut self.connection);
-        let response = Response::deserialize(&mut de)?;
-        if let ClientResponse::Winner(player) = self.game.handle_response(response)? {
+        let request = self.game.winner()?;
+        if let ClientResponse::Winner(player) = self.request(request)? {
             Ok(player)
         } else {
             Err(Error::Game(GameError::CommunicationError))
@@ -96,24 +318,68 @@ impl BlockingGameClient {
         self.game.player_id()
     }
 
+    pub fn join_code(&self) -> Option<&JoinCode> {
+        self.game.join_code()
+    }
+
     pub fn game_id(&self) -> GameId {
         self.game.game_id()
     }
 }
 
-impl Play for BlockingGameClient {
+/// What we know of the given location on our opponent's field.
+fn target_cell(game: &GameClient, location: Location) -> Option<Cell> {
+    let player = game.get_player(game.player_id()).ok()?;
+    player.speculative_field().get(location).ok()
+}
+
+fn ship_placed(game: &GameClient, ship: ShipId) -> bool {
+    match game.get_player(game.player_id()) {
+        Ok(player) => player.ships().get(&ship).filter(|s| s.placed()).is_some(),
+        Err(_) => false,
+    }
+}
+
+fn into_game_error(error: Error) -> GameError {
+    match error {
+        Error::Game(e) => e,
+        _ => GameError::CommunicationError,
+    }
+}
+
+impl<S: io::Read + io::Write> Play for BlockingGameClient<S> {
     fn advance(
         &mut self,
         player_a_id: PlayerId,
         player_b_id: PlayerId,
         guess: Location,
     ) -> GameResult<AttackResult> {
-        let request = self.game.advance(player_a_id, player_b_id, guess);
-        serde_json::to_writer(&mut self.connection, &request)
-            .map_err(|_| GameError::CommunicationError)?;
-        let mut de = serde_json::Deserializer::from_reader(&mut self.connection);
-        let response = Response::deserialize(&mut de).map_err(|_| GameError::CommunicationError)?;
-        if let ClientResponse::Attack(result) = self.game.handle_response(response)? {
+        if player_a_id != self.player_id() {
+            return Err(GameError::UnknownPlayer(player_a_id));
+        }
+        // If the shot landed before the connection was lost, the game state we rejoined with shows
+        // what it did. A ship it sank is among the opponent's sunk ships, as none of them could
+        // have been sunk without it.
+        let unknown = target_cell(&self.game, guess) == Some(Cell::Empty);
+        let applied = move |game: &GameClient| {
+            let result = match target_cell(game, guess) {
+                Some(Cell::Hit) if unknown => {
+                    let sunk = game.sunk_ships(player_b_id);
+                    match sunk.iter().find(|ship| ship.contains(guess)) {
+                        Some(ship) => AttackResult::Sunk(ship.name()),
+                        None => AttackResult::Hit,
+                    }
+                }
+                Some(Cell::Miss) if unknown => AttackResult::Miss,
+                _ => return None,
+            };
+            Some(ClientResponse::Attack(result))
+        };
+        let request = self.game.advance(player_b_id, guess)?;
+        let response = self
+            .request_unless_applied(request, applied)
+            .map_err(into_game_error)?;
+        if let ClientResponse::Attack(result) = response {
             Ok(result)
         } else {
             Err(GameError::CommunicationError)
@@ -135,12 +401,20 @@ impl Play for BlockingGameClient {
         location: Location,
         direction: Direction,
     ) -> GameResult<()> {
-        let request = self.game.place_ship(player_id, ship, location, direction);
-        serde_json::to_writer(&mut self.connection, &request)
-            .map_err(|_| GameError::CommunicationError)?;
-        let mut de = serde_json::Deserializer::from_reader(&mut self.connection);
-        let response = Response::deserialize(&mut de).map_err(|_| GameError::CommunicationError)?;
-        self.game.handle_response(response)?;
+        if player_id != self.player_id() {
+            return Err(GameError::UnknownPlayer(player_id));
+        }
+        let already_placed = ship_placed(&self.game, ship);
+        let applied = move |game: &GameClient| {
+            if !already_placed && ship_placed(game, ship) {
+                Some(ClientResponse::None)
+            } else {
+                None
+            }
+        };
+        let request = self.game.place_ship(ship, location, direction)?;
+        self.request_unless_applied(request, applied)
+            .map_err(into_game_error)?;
         Ok(())
     }
 
@@ -148,3 +422,163 @@ impl Play for BlockingGameClient {
         self.game.get_player(player_id)
     }
 }
+
+#[test]
+fn test_reconnect() {
+    use crate::server::blocking::BlockingGameServer;
+    use std::net::{Shutdown, TcpListener};
+    use std::sync::Mutex;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = BlockingGameServer::new();
+    let accepted = Mutex::new(vec![]);
+    crossbeam_utils::thread::scope(|scope| {
+        // Both players connect, then both reconnect.
+        scope.spawn(|scope| {
+            for connection in listener.incoming().take(4) {
+                let connection = connection.unwrap();
+                accepted
+                    .lock()
+                    .unwrap()
+                    .push(connection.try_clone().unwrap());
+                let server = &server;
+                scope.spawn(move |_| server.process_requests(connection));
+            }
+        });
+
+        let connect = move || TcpStream::connect(address);
+        let mut a = BlockingGameClient::connect_with(connect).unwrap();
+        a.create_game("a").unwrap();
+        let mut b = BlockingGameClient::connect_with(connect).unwrap();
+        b.join_game(a.game_id(), "b").unwrap();
+        let (player_a, player_b) = (a.player_id(), b.player_id());
+        // Catch up on the game, so the next event has to come over a new connection.
+        while !matches!(b.next_event().unwrap(), GameEvent::FirstTurn { .. }) {}
+        for connection in accepted.lock().unwrap().drain(..) {
+            connection.shutdown(Shutdown::Both).unwrap();
+        }
+
+        assert_eq!(a.winner().unwrap(), None);
+        assert_eq!(a.player_id(), player_a);
+        // The old connection may have been told about `a` leaving before it was shut down.
+        loop {
+            match b.next_event().unwrap() {
+                GameEvent::OpponentDisconnected(player_id) => assert_eq!(player_id, player_a),
+                GameEvent::OpponentJoined(player_id, _) => break assert_eq!(player_id, player_a),
+                e => panic!("unexpected event {:?}", e),
+            }
+        }
+        assert_eq!(b.player_id(), player_b);
+    })
+    .unwrap();
+}
+
+#[test]
+fn test_reconnect_does_not_repeat_moves() {
+    use crate::server::blocking::Listener as _;
+    use crate::testkit::{self, MemoryStream};
+    use crate::FirstTurn;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /// Loses the connection when told to, as soon as the answer to what was sent last arrives.
+    struct Flaky {
+        stream: MemoryStream,
+        cut: Arc<AtomicBool>,
+    }
+
+    impl io::Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = self.stream.read(buf)?;
+            if self.cut.swap(false, Ordering::SeqCst) {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+            Ok(read)
+        }
+    }
+
+    impl io::Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.stream.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.stream.flush()
+        }
+    }
+
+    fn ship_ids<S: io::Read + io::Write>(client: &BlockingGameClient<S>) -> Vec<ShipId> {
+        let player = client.get_player(client.player_id()).unwrap();
+        let mut ship_ids: Vec<_> = player.ships().into_keys().collect();
+        ship_ids.sort();
+        ship_ids
+    }
+
+    let server = testkit::server();
+    let (listener, connector) = testkit::listener();
+    let cut = Arc::new(AtomicBool::new(false));
+    crossbeam_utils::thread::scope(|scope| {
+        scope.spawn(|scope| {
+            for connection in listener.incoming().flatten() {
+                let server = &server;
+                scope.spawn(move |_| server.process_requests(connection));
+            }
+        });
+
+        let open = {
+            let (connector, cut) = (connector.clone(), cut.clone());
+            move || {
+                let stream = connector.connect()?;
+                let cut = cut.clone();
+                Ok(Flaky { stream, cut })
+            }
+        };
+        let mut a = BlockingGameClient::connect_with(open).unwrap();
+        let options = GameOptions {
+            first_turn: FirstTurn::Creator,
+            ..GameOptions::default()
+        };
+        a.create_custom_game("a", options).unwrap();
+        let mut b = connector.client().unwrap();
+        b.join_game(a.game_id(), "b").unwrap();
+        let (player_a, player_b) = (a.player_id(), b.player_id());
+        for (row, ship_id) in ship_ids(&b).into_iter().enumerate() {
+            let location = Location { column: 0, row };
+            b.place_ship(player_b, ship_id, location, Direction::East)
+                .unwrap();
+        }
+        // Nothing else is on its way to `a`, so the next thing it reads is an answer.
+        while !matches!(a.next_event().unwrap(), GameEvent::OpponentReady(_)) {}
+
+        for (row, ship_id) in ship_ids(&a).into_iter().enumerate() {
+            cut.store(row == 0, Ordering::SeqCst);
+            let location = Location { column: 0, row };
+            a.place_ship(player_a, ship_id, location, Direction::East)
+                .unwrap();
+        }
+        while !matches!(a.next_event().unwrap(), GameEvent::Turn(p) if p == player_a) {}
+
+        cut.store(true, Ordering::SeqCst);
+        let target = Location { column: 0, row: 0 };
+        assert_eq!(
+            a.advance(player_a, player_b, target).unwrap(),
+            AttackResult::Hit
+        );
+        assert!(!a.game.is_my_turn());
+
+        // The patrol boat, two long, sinks with the second of these.
+        for (column, cut_first) in [(0, false), (1, true)] {
+            b.advance(player_b, player_a, Location { column, row: 9 })
+                .unwrap();
+            while !matches!(a.next_event().unwrap(), GameEvent::Turn(p) if p == player_a) {}
+            cut.store(cut_first, Ordering::SeqCst);
+            let target = Location { column, row: 4 };
+            let result = a.advance(player_a, player_b, target).unwrap();
+            assert!(result.is_hit());
+            assert_eq!(matches!(result, AttackResult::Sunk(_)), column == 1);
+        }
+        drop(connector);
+    })
+    .unwrap();
+}
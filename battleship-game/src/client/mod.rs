@@ -1,47 +1,133 @@
-use super::protocol::{Request, Response};
-use super::{AttackResult, Direction, Error, GameId, Location, Player, PlayerId, Result, ShipId};
+use super::protocol::{
+    self, ChatMessage, ClientMessage, GameEvent, GameState, OpenGame, Request, RequestId, Response,
+    SeriesScore, ServerMessage,
+};
+use super::transport::Codec;
+use super::{
+    AttackResult, Direction, Error, GameId, GameOptions, JoinCode, Location, Player, PlayerId,
+    PlayerToken, Result, Ship, ShipId,
+};
+use std::collections::HashMap;
 
 pub mod blocking;
 
 pub enum ClientResponse {
     Attack(AttackResult),
     Winner(Option<PlayerId>),
+    OpponentDisconnected(PlayerId),
+    Events(Vec<GameEvent>),
+    /// Everything we knew about the game has been replaced with this.
+    GameState(Box<GameState>),
+    OpenGames(Vec<OpenGame>),
+    /// The server passed our chat message on to our opponents.
+    ChatSent(ChatMessage),
+    /// The server refused to carry out the given request.
+    Rejected(Request, Error),
     None,
 }
 
 pub struct GameClient {
     game_id: Option<GameId>,
+    /// Set when joining a private game.
+    join_code: Option<JoinCode>,
     player: Option<Player>,
     player_id: Option<PlayerId>,
+    token: Option<PlayerToken>,
     other_players: Vec<PlayerId>,
+    /// Each opponent's sunk ships, as of the last `GameState`.
+    sunk_ships: HashMap<PlayerId, Vec<Ship>>,
+    turn: Option<PlayerId>,
+    series: Option<SeriesScore>,
+    next_request_id: u64,
+    pending: HashMap<RequestId, Request>,
+    codec: Codec,
 }
 
 impl GameClient {
     pub fn new() -> Self {
         Self {
             game_id: None,
+            join_code: None,
             player: None,
             player_id: None,
+            token: None,
             other_players: vec![],
+            sunk_ships: HashMap::new(),
+            turn: None,
+            series: None,
+            next_request_id: 0,
+            pending: HashMap::new(),
+            codec: Codec::Json,
         }
     }
 
-    pub fn create_game(&mut self) -> Request {
-        Request::CreateGame
+    /// Tag the request with a fresh id, and remember it until its response arrives.
+    fn send(&mut self, request: Request) -> ClientMessage {
+        let id = RequestId(self.next_request_id);
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        self.pending.insert(id, request.clone());
+        ClientMessage { id, request }
+    }
+
+    pub fn hello(&mut self) -> ClientMessage {
+        self.send(Request::Hello {
+            version: protocol::PROTOCOL_VERSION,
+            capabilities: protocol::CAPABILITIES.to_vec(),
+        })
+    }
+
+    pub fn create_game(&mut self) -> ClientMessage {
+        self.send(Request::CreateGame)
+    }
+
+    pub fn create_private_game(&mut self) -> ClientMessage {
+        self.send(Request::CreatePrivateGame)
+    }
+
+    pub fn create_custom_game(&mut self, options: GameOptions) -> ClientMessage {
+        self.send(Request::CreateCustomGame(options))
     }
 
     pub fn join_game(&mut self, game_id: GameId) {
         self.game_id = Some(game_id);
+        self.join_code = None;
+    }
+
+    /// Join the private game with the given code when adding our player.
+    pub fn join_with_code(&mut self, join_code: JoinCode) {
+        self.game_id = None;
+        self.join_code = Some(join_code);
     }
 
-    pub fn rejoin_game(&mut self, player_id: PlayerId) -> Request {
-        self.game_id = Some(player_id.game_id());
-        Request::JoinGame(player_id)
+    /// Whether we have a player to take back with `rejoin_game` on another connection.
+    pub fn can_rejoin(&self) -> bool {
+        self.token.is_some()
     }
 
-    pub fn add_player(&mut self, name: &str) -> Request {
+    pub fn rejoin_game(&mut self, token: PlayerToken) -> ClientMessage {
+        self.token = Some(token.clone());
+        self.send(Request::JoinGame(token))
+    }
+
+    /// Add our player to the game we were told to join.
+    pub fn add_player(&mut self, name: &str) -> Result<ClientMessage> {
+        let request = match (&self.join_code, self.game_id) {
+            (Some(join_code), _) => Request::JoinWithCode(join_code.clone(), name.into()),
+            (None, Some(game_id)) => Request::AddPlayer(game_id, name.into()),
+            (None, None) => return Err(Error::NoGameChosen),
+        };
         self.player = Some(Player::new(name));
-        Request::AddPlayer(self.game_id.unwrap(), name.into())
+        Ok(self.send(request))
+    }
+
+    pub fn list_open_games(&mut self) -> ClientMessage {
+        self.send(Request::ListOpenGames)
+    }
+
+    /// Play against whoever the server finds for us, instead of creating or joining a game.
+    pub fn quick_match(&mut self, name: &str) -> ClientMessage {
+        self.player = Some(Player::new(name));
+        self.send(Request::QuickMatch(name.into()))
     }
 
     pub fn player(&mut self) -> Result<&mut Player> {
@@ -52,13 +138,49 @@ impl GameClient {
         }
     }
 
-    pub fn handle_response(&mut self, response: Response) -> Result<ClientResponse> {
+    pub fn handle_response(&mut self, message: ServerMessage) -> Result<ClientResponse> {
+        let request = match message.id {
+            Some(id) => Some(self.pending.remove(&id).ok_or(Error::CommunicationError)?),
+            None => None,
+        };
+        match (request, message.response) {
+            (Some(request), Response::Error(error)) => Ok(ClientResponse::Rejected(request, error)),
+            (Some(Request::Chat(_, message)), Response::ChatSent) => {
+                Ok(ClientResponse::ChatSent(message))
+            }
+            (_, response) => self.apply_response(response),
+        }
+    }
+
+    fn apply_response(&mut self, response: Response) -> Result<ClientResponse> {
         match response {
-            Response::AddPlayer(id) => {
+            Response::Hello {
+                version,
+                capabilities,
+            } => {
+                if protocol::is_supported_version(version) {
+                    self.codec = Codec::negotiate(&capabilities);
+                    Ok(ClientResponse::None)
+                } else {
+                    Err(Error::UnsupportedProtocolVersion(version))
+                }
+            }
+            Response::AddPlayer(id, token) => {
+                self.game_id = Some(id.game_id());
                 self.player_id = Some(id);
+                self.token = Some(token);
                 Ok(ClientResponse::None)
             }
+            Response::QuickMatch(id, token) => {
+                self.game_id = Some(id.game_id());
+                self.player_id = Some(id);
+                self.token = Some(token);
+                Ok(ClientResponse::None)
+            }
+            Response::OpenGames(open_games) => Ok(ClientResponse::OpenGames(open_games)),
+            Response::ChatSent | Response::Rematch => Ok(ClientResponse::None),
             Response::Advance(location, result) => {
+                self.turn = None;
                 if result.is_hit() {
                     self.player()?.speculative_field.record_hit(location)?;
                 } else {
@@ -88,48 +210,157 @@ impl GameClient {
                 self.join_game(game_id);
                 Ok(ClientResponse::None)
             }
+            Response::CreatePrivateGame(game_id, join_code) => {
+                self.join_with_code(join_code);
+                self.game_id = Some(game_id);
+                Ok(ClientResponse::None)
+            }
             Response::Winner(player_id) => Ok(ClientResponse::Winner(player_id)),
+            Response::OpponentDisconnected(player_id) => {
+                Ok(ClientResponse::OpponentDisconnected(player_id))
+            }
             Response::JoinedGame(player_id, player) => {
+                // The game moved on to a rematch while we were away.
+                if self.game_id != Some(player_id.game_id()) {
+                    self.other_players.clear();
+                }
+                self.game_id = Some(player_id.game_id());
                 self.player_id = Some(player_id);
                 self.player = Some(player);
                 Ok(ClientResponse::None)
             }
+            Response::GameState(state) => {
+                self.game_id = Some(state.player_id.game_id());
+                self.player_id = Some(state.player_id);
+                self.player = Some(state.player.clone());
+                self.other_players = state.opponents.iter().map(|o| o.player_id).collect();
+                self.sunk_ships = state
+                    .opponents
+                    .iter()
+                    .map(|o| (o.player_id, o.sunk.clone()))
+                    .collect();
+                self.turn = state.turn;
+                self.series = state.series.clone();
+                Ok(ClientResponse::GameState(state))
+            }
+            Response::Subscribed(events) => {
+                for event in &events {
+                    self.apply_event(event)?;
+                }
+                Ok(ClientResponse::Events(events))
+            }
+            Response::Event(event) => {
+                self.apply_event(&event)?;
+                Ok(ClientResponse::Events(vec![event]))
+            }
         }
     }
 
-    pub fn advance(
-        &self,
-        player_a_id: PlayerId,
-        player_b_id: PlayerId,
-        guess: Location,
-    ) -> Request {
-        Request::Advance(player_a_id, player_b_id, guess)
+    fn apply_event(&mut self, event: &GameEvent) -> Result<()> {
+        match event {
+            GameEvent::OpponentJoined(player_id, _) if !self.other_players.contains(player_id) => {
+                self.other_players.push(*player_id);
+            }
+            GameEvent::ShotFired {
+                target,
+                location,
+                result,
+                ..
+            } if Some(*target) == self.player_id => {
+                if result.is_hit() {
+                    self.player()?.own_field.record_hit(*location)?;
+                } else {
+                    self.player()?.own_field.record_miss(*location)?;
+                }
+            }
+            GameEvent::Turn(player_id) => self.turn = Some(*player_id),
+            GameEvent::GameOver { .. } => self.turn = None,
+            GameEvent::Rematch { game_id, players } => {
+                let new_id = |old| players.iter().find(|&&(o, _)| o == old).map(|&(_, n)| n);
+                let player_id = self.player_id.and_then(new_id);
+                self.other_players = players
+                    .iter()
+                    .map(|&(_, n)| n)
+                    .filter(|&n| Some(n) != player_id)
+                    .collect();
+                self.player_id = player_id;
+                self.game_id = Some(*game_id);
+                self.sunk_ships.clear();
+                self.turn = None;
+                self.series = None;
+                let name = self.player()?.name().to_owned();
+                self.player = Some(Player::new(&name));
+            }
+            GameEvent::Series(series) => self.series = Some(series.clone()),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn advance(&mut self, player_b_id: PlayerId, guess: Location) -> Result<ClientMessage> {
+        let token = self.token()?;
+        Ok(self.send(Request::Advance(token, player_b_id, guess)))
     }
 
     pub fn place_ship(
-        &self,
-        player_id: PlayerId,
+        &mut self,
         ship_id: ShipId,
         location: Location,
         direction: Direction,
-    ) -> Request {
-        Request::PlaceShip(player_id, ship_id, location, direction)
+    ) -> Result<ClientMessage> {
+        let token = self.token()?;
+        Ok(self.send(Request::PlaceShip(token, ship_id, location, direction)))
     }
 
-    pub fn wait_for_turn(&self) -> Request {
-        Request::WaitForTurn(self.player_id.unwrap())
+    /// Whether we are still waiting to hear if the given ship was placed.
+    pub fn ship_placement_pending(&self, ship_id: ShipId) -> bool {
+        self.pending
+            .values()
+            .any(|r| matches!(r, Request::PlaceShip(_, id, ..) if *id == ship_id))
+    }
+
+    pub fn chat(&mut self, message: ChatMessage) -> Result<ClientMessage> {
+        let token = self.token()?;
+        Ok(self.send(Request::Chat(token, message)))
+    }
+
+    /// Offer or accept a rematch. Once everyone has, our player moves to the new game.
+    pub fn rematch(&mut self) -> Result<ClientMessage> {
+        let token = self.token()?;
+        Ok(self.send(Request::Rematch(token)))
+    }
+
+    /// Ask for everything about our game, to start over from.
+    pub fn get_game_state(&mut self) -> Result<ClientMessage> {
+        let player_id = self.player_id.ok_or(Error::NoGameChosen)?;
+        Ok(self.send(Request::GetGameState(player_id)))
+    }
+
+    pub fn subscribe(&mut self) -> Result<ClientMessage> {
+        let token = self.token()?;
+        Ok(self.send(Request::Subscribe(token)))
+    }
+
+    /// Whether it is our turn, going by the events we have seen.
+    pub fn is_my_turn(&self) -> bool {
+        self.turn.is_some() && self.turn == self.player_id
     }
 
     pub fn get_player(&self, player_id: PlayerId) -> Result<&Player> {
-        if self.player_id == Some(player_id) && self.player.is_some() {
-            Ok(self.player.as_ref().unwrap())
-        } else {
-            Err(Error::UnknownPlayer(player_id))
+        match &self.player {
+            Some(player) if self.player_id == Some(player_id) => Ok(player),
+            _ => Err(Error::UnknownPlayer(player_id)),
         }
     }
 
-    pub fn winner(&self) -> Request {
-        Request::Winner(self.game_id.unwrap())
+    /// The opponent's ships which had been sunk when we last got the `GameState`.
+    pub fn sunk_ships(&self, player_id: PlayerId) -> &[Ship] {
+        self.sunk_ships.get(&player_id).map_or(&[], |ships| ships)
+    }
+
+    pub fn winner(&mut self) -> Result<ClientMessage> {
+        let game_id = self.game_id.ok_or(Error::NoGameChosen)?;
+        Ok(self.send(Request::Winner(game_id)))
     }
 
     pub fn other_player_ids(&self) -> Vec<PlayerId> {
@@ -140,7 +371,101 @@ impl GameClient {
         self.player_id.unwrap()
     }
 
+    /// Our player, if we have joined a game yet.
+    pub fn joined_player_id(&self) -> Option<PlayerId> {
+        self.player_id
+    }
+
+    /// The token which lets us act as our player, once we have one.
+    pub fn token(&self) -> Result<PlayerToken> {
+        self.token.clone().ok_or(Error::NoGameChosen)
+    }
+
     pub fn game_id(&self) -> GameId {
         self.game_id.unwrap()
     }
+
+    /// The code to give others so they can join, if the game is private.
+    pub fn join_code(&self) -> Option<&JoinCode> {
+        self.join_code.as_ref()
+    }
+
+    /// How the series is going, if the game is part of one.
+    pub fn series(&self) -> Option<&SeriesScore> {
+        self.series.as_ref()
+    }
+
+    /// The codec agreed on with the server, to be used for everything after its `Hello`.
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+}
+
+#[test]
+fn test_responses_matched_to_requests() {
+    let mut client = GameClient::new();
+    // There is nobody to act as before joining.
+    assert!(matches!(client.token(), Err(Error::NoGameChosen)));
+    assert!(matches!(client.subscribe(), Err(Error::NoGameChosen)));
+    assert!(matches!(client.get_game_state(), Err(Error::NoGameChosen)));
+    client.join_game(GameId(1));
+    let add_player = client.add_player("a").unwrap();
+    let response = Response::AddPlayer(PlayerId(GameId(1), 1), "token".parse().unwrap());
+    client
+        .handle_response(ServerMessage {
+            id: Some(add_player.id),
+            response,
+        })
+        .unwrap();
+
+    let (north, south) = (Direction::North, Direction::South);
+    let first = client
+        .place_ship(ShipId(1), Location::new(0, 0), south)
+        .unwrap();
+    let second = client
+        .place_ship(ShipId(2), Location::new(0, 0), north)
+        .unwrap();
+    assert!(client.ship_placement_pending(ShipId(1)));
+    assert!(client.ship_placement_pending(ShipId(2)));
+
+    let response = Response::Error(Error::InvalidShipLocation(Location::new(0, 0), north));
+    assert!(matches!(
+        client.handle_response(ServerMessage {
+            id: Some(second.id),
+            response
+        }),
+        Ok(ClientResponse::Rejected(
+            Request::PlaceShip(_, ShipId(2), ..),
+            _
+        ))
+    ));
+    assert!(!client.ship_placement_pending(ShipId(2)));
+
+    let response = Response::PlaceShip(ShipId(1), Location::new(0, 0), south);
+    client
+        .handle_response(ServerMessage {
+            id: Some(first.id),
+            response,
+        })
+        .unwrap();
+    assert!(!client.ship_placement_pending(ShipId(1)));
+    assert!(client.player().unwrap().ships()[&ShipId(1)].placed());
+
+    let chat = client.chat(ChatMessage::Text("hi".into())).unwrap();
+    assert!(matches!(
+        client.handle_response(ServerMessage {
+            id: Some(chat.id),
+            response: Response::ChatSent
+        }),
+        Ok(ClientResponse::ChatSent(ChatMessage::Text(text))) if text == "hi"
+    ));
+
+    let response = Response::Winner(None);
+    assert!(matches!(
+        client.handle_response(ServerMessage {
+            id: Some(first.id),
+            response
+        }),
+        Err(Error::CommunicationError)
+    ));
 }
@@ -1,7 +1,7 @@
 // Copyright 2020 Remi Bernotavicius
 
 use matches::matches;
-use rand::{self, rngs::ThreadRng, Rng};
+use rand::{self, rngs::StdRng, rngs::ThreadRng, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::{fmt, ops, result, str};
@@ -9,6 +9,9 @@ use std::{fmt, ops, result, str};
 pub mod client;
 pub mod protocol;
 pub mod server;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+pub mod transport;
 
 const MAX_PLAYERS: usize = 2;
 
@@ -70,6 +73,79 @@ impl PlayerId {
     }
 }
 
+/// Secret handed out to a player when they join a game. Any request acting on behalf of a player
+/// must present it, so unlike the `PlayerId` it must never be shown to other players.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct PlayerToken(String);
+
+impl PlayerToken {
+    fn random() -> Self {
+        Self(format!("{:032x}", rand::thread_rng().gen::<u128>()))
+    }
+}
+
+impl str::FromStr for PlayerToken {
+    type Err = std::convert::Infallible;
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        Ok(Self(s.into()))
+    }
+}
+
+impl fmt::Display for PlayerToken {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.0)
+    }
+}
+
+/// Keep tokens out of logs
+impl fmt::Debug for PlayerToken {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "PlayerToken(..)")
+    }
+}
+
+/// Lets players into a private game. Short and free of easily confused characters, so it can be
+/// read out to a friend.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct JoinCode(String);
+
+const JOIN_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const JOIN_CODE_LENGTH: usize = 6;
+
+impl JoinCode {
+    fn random() -> Self {
+        let mut rng = rand::thread_rng();
+        let code = (0..JOIN_CODE_LENGTH)
+            .map(|_| JOIN_CODE_ALPHABET[rng.gen_range(0, JOIN_CODE_ALPHABET.len())] as char)
+            .collect();
+        Self(code)
+    }
+}
+
+/// Accepts codes in any case, with or without the separator.
+impl str::FromStr for JoinCode {
+    type Err = String;
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let code: String = s
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '-')
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        if code.len() == JOIN_CODE_LENGTH && code.bytes().all(|c| JOIN_CODE_ALPHABET.contains(&c)) {
+            Ok(Self(code))
+        } else {
+            Err(format!("invalid join code {}", s))
+        }
+    }
+}
+
+impl fmt::Display for JoinCode {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let (first, second) = self.0.split_at(JOIN_CODE_LENGTH / 2);
+        write!(fmt, "{}-{}", first, second)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Error {
     InvalidLocation(Location),
@@ -82,6 +158,41 @@ pub enum Error {
     NotYourTurn(String),
     TooManyPlayers,
     CommunicationError,
+    InvalidPlayerToken,
+    PlayerNotOnConnection(PlayerId),
+    HandshakeRequired,
+    UnsupportedProtocolVersion(u32),
+    MalformedMessage,
+    MessageTooLarge,
+    /// The game no longer exists, and this is why.
+    GameRemoved(GameId, GameRemoval),
+    /// The game is private, so it can only be joined with its `JoinCode`.
+    JoinCodeRequired(GameId),
+    UnknownJoinCode,
+    /// Chat messages can be at most this many characters long.
+    ChatTooLong(usize),
+    EmptyChatMessage,
+    /// The player sent too many chat messages recently.
+    ChatRateLimited,
+    /// Series must be one of `SERIES_LENGTHS` games long.
+    InvalidSeriesLength(u32),
+    /// A rematch can only be asked for once the game is over.
+    GameNotOver,
+    ShipAlreadyPlaced(String),
+    /// The location was already attacked.
+    AlreadyAttacked(Location),
+    /// Nobody can take a turn until everyone has joined and placed their ships.
+    GameNotStarted,
+    /// The client hasn't created or been given a game to join.
+    NoGameChosen,
+    /// Player names can be at most this many characters long.
+    NameTooLong(usize),
+    /// A connection can have at most this many of the games it created around at once.
+    TooManyGames(usize),
+    /// The connection sent too many requests recently.
+    RateLimited,
+    /// The server is already serving as many connections as it will.
+    ServerFull,
 }
 
 impl fmt::Display for Error {
@@ -95,22 +206,219 @@ impl fmt::Display for Error {
             Self::ShipPlacementConflict(name) => {
                 write!(fmt, "unable to place ship, conflict with {}", name)
             }
+            Self::ShipAlreadyPlaced(name) => write!(fmt, "{} was already placed", name),
+            Self::AlreadyAttacked(loc) => write!(fmt, "{} was already attacked", loc),
             Self::UnknownPlayer(_) => write!(fmt, "unknown player"),
+            Self::InvalidPlayerToken => write!(fmt, "invalid player token"),
+            Self::PlayerNotOnConnection(player_id) => {
+                write!(
+                    fmt,
+                    "player {} has not joined on this connection",
+                    player_id
+                )
+            }
             Self::UnknownGame(_) => write!(fmt, "unknown game"),
+            Self::JoinCodeRequired(_) => write!(fmt, "game is private, ask for its join code"),
+            Self::UnknownJoinCode => write!(fmt, "no game has that join code"),
+            Self::ChatTooLong(max) => {
+                write!(fmt, "chat messages can be at most {} characters", max)
+            }
+            Self::EmptyChatMessage => write!(fmt, "chat message is empty"),
+            Self::ChatRateLimited => write!(fmt, "too many chat messages, slow down"),
+            Self::InvalidSeriesLength(best_of) => {
+                write!(fmt, "a series can't be best of {} games", best_of)
+            }
+            Self::GameNotOver => write!(fmt, "the game isn't over yet"),
+            Self::GameNotStarted => write!(fmt, "the game hasn't started yet"),
+            Self::NoGameChosen => write!(fmt, "no game was chosen to join"),
+            Self::NameTooLong(max) => write!(fmt, "names can be at most {} characters", max),
+            Self::TooManyGames(max) => {
+                write!(fmt, "a connection can create at most {} games at once", max)
+            }
+            Self::RateLimited => write!(fmt, "too many requests, slow down"),
+            Self::ServerFull => write!(fmt, "the server is full, try again later"),
             Self::NotYourTurn(player) => write!(fmt, "it is not {}'s turn", player),
             Self::TooManyPlayers => write!(fmt, "too many players"),
             Self::InvalidSelfAttack => write!(fmt, "cannot attack yourself"),
+            Self::HandshakeRequired => write!(fmt, "expected Hello"),
+            Self::UnsupportedProtocolVersion(version) => write!(
+                fmt,
+                "protocol version {} is not supported, expected {} through {}",
+                version,
+                protocol::MIN_PROTOCOL_VERSION,
+                protocol::PROTOCOL_VERSION
+            ),
+            Self::MalformedMessage => write!(fmt, "malformed message"),
+            Self::MessageTooLarge => write!(fmt, "message too large"),
             Self::CommunicationError => write!(fmt, "communication error"),
+            Self::GameRemoved(_, removal) => write!(fmt, "unknown game, {}", removal),
         }
     }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Why the server stopped keeping a game around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum GameRemoval {
+    /// Nobody joined the game after it was created.
+    Abandoned,
+    Finished,
+    /// Nobody made a move in too long.
+    Idle,
+}
+
+impl fmt::Display for GameRemoval {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Abandoned => write!(fmt, "it was removed because nobody joined"),
+            Self::Finished => write!(fmt, "it was removed after it finished"),
+            Self::Idle => write!(fmt, "it was removed after nobody played for too long"),
+        }
+    }
+}
+
+/// What a game is played with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rules {
+    pub width: usize,
+    pub height: usize,
+    /// The names of the ships each player places.
+    pub ships: Vec<String>,
+    /// How many games long the series is.
+    pub best_of: u32,
+}
+
+impl fmt::Display for Rules {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{}x{}, {} ships",
+            self.width,
+            self.height,
+            self.ships.len()
+        )?;
+        if self.best_of > 1 {
+            write!(fmt, ", best of {}", self.best_of)?;
+        }
+        Ok(())
+    }
+}
+
+/// How many games a series may be played over.
+pub const SERIES_LENGTHS: &[u32] = &[1, 3, 5, 7];
+
+/// Chosen by whoever creates a game.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GameOptions {
+    /// Keep playing rematches until someone has won a majority of this many games.
+    pub best_of: u32,
+    /// Only those given the game's `JoinCode` can join.
+    pub private: bool,
+    pub first_turn: FirstTurn,
+}
+
+impl Default for GameOptions {
+    fn default() -> Self {
+        Self {
+            best_of: 1,
+            private: false,
+            first_turn: FirstTurn::default(),
+        }
+    }
+}
+
+/// How the player who shoots first is chosen.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FirstTurn {
+    /// Whoever joined the game first.
+    Creator,
+    /// Whoever joined the game second.
+    Joiner,
+    /// Chosen at random, using a seed the game keeps.
+    CoinFlip,
+    /// Whoever lost the previous game of a rematch, or by coin flip for the first game.
+    #[default]
+    Loser,
+}
+
+impl fmt::Display for FirstTurn {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Creator => write!(fmt, "as the creator"),
+            Self::Joiner => write!(fmt, "as the joiner"),
+            Self::CoinFlip => write!(fmt, "by coin flip"),
+            Self::Loser => write!(fmt, "as the loser of the last game"),
+        }
+    }
+}
+
+/// The games won so far in a series, by seat, not counting the one being played.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Series {
+    best_of: u32,
+    wins: Vec<u32>,
+}
+
+impl Default for Series {
+    fn default() -> Self {
+        Self {
+            best_of: 1,
+            wins: vec![],
+        }
+    }
+}
+
+/// How far along a game is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GamePhase {
+    WaitingForPlayers,
+    PlacingShips,
+    InProgress,
+    Over { winner: PlayerId },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Game {
     id: GameId,
+    #[serde(with = "player_list")]
     players: HashMap<PlayerId, Player>,
     current_turn: Option<PlayerId>,
+    /// Only private games have one.
+    #[serde(default)]
+    join_code: Option<JoinCode>,
+    #[serde(default)]
+    series: Series,
+    #[serde(default)]
+    first_turn: FirstTurn,
+    /// Decides any coin flip.
+    #[serde(default)]
+    seed: u64,
+    /// The seat of whoever lost the game this is a rematch of.
+    #[serde(default)]
+    previous_loser: Option<usize>,
+}
+
+/// `PlayerId` can't be a key in a JSON object, so the players are stored as a list instead.
+mod player_list {
+    use super::{Player, PlayerId};
+    use serde::{Deserialize as _, Deserializer, Serializer};
+    use std::collections::HashMap;
+
+    pub fn serialize<S: Serializer>(
+        players: &HashMap<PlayerId, Player>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(players)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<PlayerId, Player>, D::Error> {
+        Ok(Vec::<(PlayerId, Player)>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
 }
 
 impl Game {
@@ -119,9 +427,32 @@ impl Game {
             id,
             players: HashMap::new(),
             current_turn: None,
+            join_code: None,
+            series: Series::default(),
+            first_turn: FirstTurn::default(),
+            seed: rand::thread_rng().gen(),
+            previous_loser: None,
         }
     }
 
+    /// A game which can only be joined with the returned code.
+    pub fn new_private(id: GameId) -> (Self, JoinCode) {
+        let join_code = JoinCode::random();
+        let game = Self {
+            join_code: Some(join_code.clone()),
+            ..Self::new(id)
+        };
+        (game, join_code)
+    }
+
+    pub fn id(&self) -> GameId {
+        self.id
+    }
+
+    pub fn join_code(&self) -> Option<&JoinCode> {
+        self.join_code.as_ref()
+    }
+
     pub fn add_player(&mut self, name: &str) -> Result<PlayerId> {
         if self.players.len() >= MAX_PLAYERS {
             return Err(Error::TooManyPlayers);
@@ -135,10 +466,32 @@ impl Game {
             .unwrap_or(PlayerId(self.id, 0));
         let id = max_id.incr();
         self.give_player(id, Player::new(name));
-        self.current_turn = Some(id);
+        self.current_turn = Some(self.first_turn().map_or(id, |(first, _)| first));
         Ok(id)
     }
 
+    pub fn set_first_turn(&mut self, first_turn: FirstTurn) {
+        self.first_turn = first_turn;
+    }
+
+    /// Who shoots first and how they were chosen, known once everyone has joined.
+    pub fn first_turn(&self) -> Option<(PlayerId, FirstTurn)> {
+        if self.players.len() < MAX_PLAYERS {
+            return None;
+        }
+        let coin_flip = || StdRng::seed_from_u64(self.seed).gen_range(0, MAX_PLAYERS) + 1;
+        let (seat, chosen_by) = match (self.first_turn, self.previous_loser) {
+            (FirstTurn::Creator, _) => (1, FirstTurn::Creator),
+            (FirstTurn::Joiner, _) => (MAX_PLAYERS, FirstTurn::Joiner),
+            (FirstTurn::CoinFlip, _) | (FirstTurn::Loser, None) => {
+                (coin_flip(), FirstTurn::CoinFlip)
+            }
+            (FirstTurn::Loser, Some(seat)) => (seat, FirstTurn::Loser),
+        };
+        let player_id = self.players.keys().find(|p| p.1 == seat)?;
+        Some((*player_id, chosen_by))
+    }
+
     pub fn get_player_mut(&mut self, player_id: PlayerId) -> Result<&mut Player> {
         self.players
             .get_mut(&player_id)
@@ -152,16 +505,34 @@ impl Game {
     }
 
     fn give_player(&mut self, player_id: PlayerId, player: Player) {
-        let res = self.players.insert(player_id, player);
-        assert!(res.is_none());
+        self.players.insert(player_id, player);
     }
 
-    fn next_turn(&mut self) {
-        let current = self.current_turn.unwrap();
-        let mut iter = self.players.keys().cycle().skip_while(|&&k| k != current);
-        let next = iter.next();
-        assert_eq!(next, Some(&current));
-        self.current_turn = Some(*iter.next().unwrap());
+    /// Make sure both players exist before either is taken out of the game to attack.
+    fn check_attack(&self, player_a_id: PlayerId, player_b_id: PlayerId) -> Result<()> {
+        let current_turn = self.current_turn().ok_or(Error::GameNotStarted)?;
+        if player_a_id != current_turn {
+            return Err(Error::NotYourTurn(
+                self.get_player(player_a_id)?.name().into(),
+            ));
+        }
+        if player_a_id == player_b_id {
+            return Err(Error::InvalidSelfAttack);
+        }
+        self.get_player(player_b_id)?;
+        Ok(())
+    }
+
+    fn next_turn(&mut self) -> Result<()> {
+        let current = self.current_turn.ok_or(Error::GameNotStarted)?;
+        let mut players = self.get_players();
+        players.sort();
+        let index = players
+            .iter()
+            .position(|&p| p == current)
+            .ok_or(Error::UnknownPlayer(current))?;
+        self.current_turn = Some(players[(index + 1) % players.len()]);
+        Ok(())
     }
 
     pub fn current_turn(&self) -> Option<PlayerId> {
@@ -184,6 +555,104 @@ impl Game {
     pub fn get_players(&self) -> Vec<PlayerId> {
         self.players.keys().cloned().collect()
     }
+
+    pub fn phase(&self) -> GamePhase {
+        if self.players.len() < MAX_PLAYERS {
+            return GamePhase::WaitingForPlayers;
+        }
+        match (self.current_turn(), self.winner()) {
+            (None, _) => GamePhase::PlacingShips,
+            (Some(_), None) => GamePhase::InProgress,
+            (Some(_), Some(winner)) => GamePhase::Over { winner },
+        }
+    }
+
+    /// How many shots have been fired in the game so far.
+    pub fn shots_fired(&self) -> u64 {
+        let shots = self
+            .players
+            .values()
+            .flat_map(|p| p.own_field.iter())
+            .filter(|&(_, cell)| cell != Cell::Empty)
+            .count();
+        shots as u64
+    }
+
+    /// Whether the game was started and somebody won. A lone player counts as the winner, so the
+    /// game has to have started.
+    pub fn is_over(&self) -> bool {
+        self.current_turn().is_some() && self.winner().is_some()
+    }
+
+    /// Make this the first game of a series.
+    pub fn set_best_of(&mut self, best_of: u32) -> Result<()> {
+        if !SERIES_LENGTHS.contains(&best_of) {
+            return Err(Error::InvalidSeriesLength(best_of));
+        }
+        self.series = Series {
+            best_of,
+            wins: vec![],
+        };
+        Ok(())
+    }
+
+    pub fn best_of(&self) -> u32 {
+        self.series.best_of
+    }
+
+    /// How many games of the series each player has won, counting this one if it is over.
+    pub fn series_score(&self) -> Vec<(PlayerId, u32)> {
+        let winner = if self.is_over() { self.winner() } else { None };
+        let mut players = self.get_players();
+        players.sort();
+        players
+            .into_iter()
+            .map(|player_id| {
+                let seat = player_id.1 - 1;
+                let wins = self.series.wins.get(seat).cloned().unwrap_or(0);
+                (player_id, wins + (winner == Some(player_id)) as u32)
+            })
+            .collect()
+    }
+
+    /// Whoever has won a majority of the series' games, if anyone has yet.
+    pub fn series_winner(&self) -> Option<PlayerId> {
+        let best_of = self.series.best_of;
+        self.series_score()
+            .into_iter()
+            .find(|&(_, wins)| wins * 2 > best_of)
+            .map(|(player_id, _)| player_id)
+    }
+
+    /// The next game of the series with the same players, or the first game of a new series once
+    /// this one has been won. Also returns what each player's id in this game becomes in the new
+    /// one.
+    pub fn rematch(&self, id: GameId) -> Result<(Game, Vec<(PlayerId, PlayerId)>)> {
+        if !self.is_over() {
+            return Err(Error::GameNotOver);
+        }
+        let winner = self.winner();
+        let mut game = Game::new(id);
+        game.first_turn = self.first_turn;
+        game.previous_loser = self
+            .get_players()
+            .into_iter()
+            .find(|&p| Some(p) != winner)
+            .map(|p| p.1);
+        game.series.best_of = self.series.best_of;
+        if self.series_winner().is_none() {
+            game.series.wins = self.series_score().into_iter().map(|(_, w)| w).collect();
+        }
+
+        let mut players = self.get_players();
+        players.sort();
+        let mut seats = vec![];
+        for player_id in players {
+            let new_id = game.add_player(self.get_player(player_id)?.name())?;
+            seats.push((player_id, new_id));
+        }
+        Ok((game, seats))
+    }
 }
 
 pub trait Play {
@@ -218,24 +687,16 @@ impl Play for Game {
         player_b_id: PlayerId,
         guess: Location,
     ) -> Result<AttackResult> {
-        if Some(player_a_id) != self.current_turn {
-            return Err(Error::NotYourTurn(
-                self.get_player(player_a_id)?.name().into(),
-            ));
-        }
-        if player_a_id == player_b_id {
-            return Err(Error::InvalidSelfAttack);
-        }
+        self.check_attack(player_a_id, player_b_id)?;
 
         let mut player_a = self.take_player(player_a_id)?;
         let mut player_b = self.take_player(player_b_id)?;
         let res = player_a.attack(&mut player_b, guess);
         self.give_player(player_a_id, player_a);
         self.give_player(player_b_id, player_b);
-        if res.is_ok() {
-            self.next_turn();
-        }
-        res
+        let res = res?;
+        self.next_turn()?;
+        Ok(res)
     }
 
     fn advance_automatically(
@@ -243,18 +704,14 @@ impl Play for Game {
         player_a_id: PlayerId,
         player_b_id: PlayerId,
     ) -> Result<AttackResult> {
-        if Some(player_a_id) != self.current_turn {
-            return Err(Error::NotYourTurn(
-                self.get_player(player_a_id)?.name().into(),
-            ));
-        }
+        self.check_attack(player_a_id, player_b_id)?;
 
         let mut player_a = self.take_player(player_a_id)?;
         let mut player_b = self.take_player(player_b_id)?;
         let res = player_a.attack_automatically(&mut player_b);
         self.give_player(player_a_id, player_a);
         self.give_player(player_b_id, player_b);
-        self.next_turn();
+        self.next_turn()?;
         Ok(res)
     }
 
@@ -305,6 +762,18 @@ impl Player {
         &self.name
     }
 
+    /// The rules this player is playing by, as far as a single game goes.
+    pub fn rules(&self) -> Rules {
+        let mut ships: Vec<_> = self.ships.iter().collect();
+        ships.sort_by_key(|&(&id, _)| id);
+        Rules {
+            width: self.own_field.width(),
+            height: self.own_field.height(),
+            ships: ships.into_iter().map(|(_, ship)| ship.name()).collect(),
+            best_of: 1,
+        }
+    }
+
     pub fn ships(&self) -> HashMap<ShipId, Ship> {
         self.ships.clone()
     }
@@ -350,13 +819,20 @@ impl Player {
         Ok(())
     }
 
+    /// Place any ships not yet placed at random, without overlapping.
     pub fn place_ships_automatically(&mut self) {
         let mut rng = rand::thread_rng();
-        for ship in self.ships.values_mut() {
+        let unplaced: Vec<_> = self
+            .ships
+            .iter()
+            .filter(|(_, ship)| !ship.placed())
+            .map(|(&id, _)| id)
+            .collect();
+        for ship_id in unplaced {
             loop {
                 let location = Location::random(&mut rng, &self.own_field);
                 let direction = Direction::random(&mut rng);
-                if ship.place(&self.own_field, location, direction).is_ok() {
+                if self.place_ship(ship_id, location, direction).is_ok() {
                     break;
                 }
             }
@@ -372,9 +848,7 @@ impl Player {
         other_player: &mut Player,
         location: Location,
     ) -> Result<AttackResult> {
-        if !matches!(other_player.own_field.get(location)?, Cell::Empty) {
-            return Err(Error::InvalidLocation(location));
-        }
+        other_player.own_field.require_empty(location)?;
 
         let mut result = AttackResult::Miss;
         for ship in other_player.ships.values_mut() {
@@ -446,10 +920,10 @@ impl Player {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
 pub struct ShipId(usize);
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 enum ShipState {
     Healthy,
     Hit(usize),
@@ -483,7 +957,7 @@ impl fmt::Display for AttackResult {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Ship {
     kind: ShipKind,
     state: ShipState,
@@ -511,8 +985,7 @@ impl Ship {
         if let Some((location, direction)) = self.location.clone() {
             let head = location;
             for s in 0..self.size() {
-                let tail = (head + Vector::new(direction, s)).unwrap();
-                if location_in == tail {
+                if head + Vector::new(direction, s) == Some(location_in) {
                     return true;
                 }
             }
@@ -559,7 +1032,9 @@ impl Ship {
             .require_valid_location(tail)
             .map_err(|_| Error::InvalidShipLocation(location, direction))?;
 
-        assert!(self.location.is_none(), "Cannot re-place ship");
+        if self.placed() {
+            return Err(Error::ShipAlreadyPlaced(self.name()));
+        }
         self.location = Some((location, direction));
         Ok(())
     }
@@ -569,7 +1044,7 @@ impl Ship {
     }
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 enum ShipKind {
     Carrier,
     Battleship,
@@ -815,16 +1290,21 @@ impl BattleField {
         }
     }
 
+    fn require_empty(&self, location: Location) -> Result<()> {
+        match self.get(location)? {
+            Cell::Empty => Ok(()),
+            _ => Err(Error::AlreadyAttacked(location)),
+        }
+    }
+
     fn record_hit(&mut self, location: Location) -> Result<()> {
-        self.require_valid_location(location)?;
-        assert_eq!(self.get(location)?, Cell::Empty);
+        self.require_empty(location)?;
         self.field[location.row * self.width + location.column] = Cell::Hit;
         Ok(())
     }
 
     fn record_miss(&mut self, location: Location) -> Result<()> {
-        self.require_valid_location(location)?;
-        assert_eq!(self.get(location)?, Cell::Empty);
+        self.require_empty(location)?;
         self.field[location.row * self.width + location.column] = Cell::Miss;
         Ok(())
     }
@@ -835,3 +1315,42 @@ impl Default for BattleField {
         Self::new(10, 10)
     }
 }
+
+#[test]
+fn test_join_code_parsing() {
+    let code = JoinCode::random();
+    assert_eq!(code.to_string().parse(), Ok(code.clone()));
+    assert_eq!(code.to_string().to_lowercase().parse(), Ok(code.clone()));
+    assert_eq!(code.0.parse(), Ok(code));
+    assert!("ABC-DE".parse::<JoinCode>().is_err());
+    assert!("ABC-DE0".parse::<JoinCode>().is_err());
+}
+
+#[test]
+fn test_first_turn_policies() {
+    let game_with = |first_turn, seed| {
+        let mut game = Game::new(GameId(1));
+        game.set_first_turn(first_turn);
+        game.seed = seed;
+        let creator = game.add_player("a").unwrap();
+        let joiner = game.add_player("b").unwrap();
+        (game.first_turn().unwrap(), creator, joiner)
+    };
+
+    let ((first, _), creator, _) = game_with(FirstTurn::Creator, 0);
+    assert_eq!(first, creator);
+    let ((first, _), _, joiner) = game_with(FirstTurn::Joiner, 0);
+    assert_eq!(first, joiner);
+
+    // The same seed always flips the same way, and a game with no previous loser flips too.
+    let flips: Vec<_> = (0..20)
+        .map(|seed| game_with(FirstTurn::CoinFlip, seed).0)
+        .collect();
+    let loser_flips: Vec<_> = (0..20)
+        .map(|seed| game_with(FirstTurn::Loser, seed).0)
+        .collect();
+    assert_eq!(flips, loser_flips);
+    assert!(flips.iter().all(|&(_, by)| by == FirstTurn::CoinFlip));
+    assert!(flips.iter().any(|&(p, _)| p == PlayerId(GameId(1), 1)));
+    assert!(flips.iter().any(|&(p, _)| p == PlayerId(GameId(1), 2)));
+}
@@ -1,27 +1,293 @@
-use super::{AttackResult, Direction, GameId, Location, Player, PlayerId, ShipId};
+use super::{
+    AttackResult, Direction, FirstTurn, GameId, GameOptions, GamePhase, JoinCode, Location, Player,
+    PlayerId, PlayerToken, Rules, Ship, ShipId,
+};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The version of the protocol spoken by this build. It must be bumped whenever `Request` or
+/// `Response` change in a way an older peer wouldn't understand. Bincode numbers enum variants by
+/// their position, so new variants are only ever added at the end.
+pub const PROTOCOL_VERSION: u32 = 13;
+
+/// The oldest version of the protocol this build is still able to speak. Versions before 12
+/// numbered the variants of `Error` differently, and before 13 an `Opponent` had no sunk ships.
+pub const MIN_PROTOCOL_VERSION: u32 = 13;
+
+/// Optional protocol features, advertised by both sides in their `Hello`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Capability {
+    /// Can switch to `transport::Codec::Bincode` after the handshake.
+    Bincode,
+    /// A capability added by a newer version of the protocol than ours.
+    #[serde(other)]
+    Unknown,
+}
+
+/// The capabilities supported by this build.
+pub const CAPABILITIES: &[Capability] = &[Capability::Bincode];
+
+pub fn is_supported_version(version: u32) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
+    /// Must be the first request sent on any connection.
+    Hello {
+        version: u32,
+        capabilities: Vec<Capability>,
+    },
+    /// Only works for public games, private ones need `JoinWithCode`.
     AddPlayer(GameId, String),
     CreateGame,
-    JoinGame(PlayerId),
-    PlaceShip(PlayerId, ShipId, Location, Direction),
-    Advance(PlayerId, PlayerId, Location),
-    WaitForTurn(PlayerId),
+    /// Create a game which isn't listed, and can only be joined with its code.
+    CreatePrivateGame,
+    /// Answered with `Response::CreateGame` or `Response::CreatePrivateGame`, depending on the
+    /// options.
+    CreateCustomGame(GameOptions),
+    /// Add a player to the private game with the given code. Answered with `Response::AddPlayer`.
+    JoinWithCode(JoinCode, String),
+    JoinGame(PlayerToken),
+    PlaceShip(PlayerToken, ShipId, Location, Direction),
+    Advance(PlayerToken, PlayerId, Location),
+    /// Answered once it is the player's turn, with the shot the opponent took meanwhile.
+    /// `Subscribe` is the better way to follow a game, but this stays served because removing it
+    /// would renumber the requests after it.
+    WaitForTurn(PlayerToken),
     Winner(GameId),
+    /// Have the player's `GameEvent`s pushed to this connection.
+    Subscribe(PlayerToken),
+    /// The games waiting for an opponent, longest waiting first. Only the first hundred are listed.
+    ListOpenGames,
+    /// Join the longest waiting open game as the named player, or create a game if there are none.
+    QuickMatch(String),
+    /// Say something to the player's opponents.
+    Chat(PlayerToken, ChatMessage),
+    /// Offer or accept a rematch once the game is over. When all players have asked for one, the
+    /// new game is announced with `GameEvent::Rematch`.
+    Rematch(PlayerToken),
+    /// Everything the given player can know about their game. The player must have joined on this
+    /// connection.
+    GetGameState(PlayerId),
+}
+
+impl Request {
+    /// The token of the player this request acts on behalf of, if any.
+    pub fn player_token(&self) -> Option<&PlayerToken> {
+        match self {
+            Self::JoinGame(token)
+            | Self::PlaceShip(token, ..)
+            | Self::Advance(token, ..)
+            | Self::WaitForTurn(token)
+            | Self::Subscribe(token)
+            | Self::Chat(token, _)
+            | Self::Rematch(token) => Some(token),
+            Self::Hello { .. }
+            | Self::AddPlayer(..)
+            | Self::CreateGame
+            | Self::CreatePrivateGame
+            | Self::CreateCustomGame(_)
+            | Self::JoinWithCode(..)
+            | Self::Winner(_)
+            | Self::ListOpenGames
+            | Self::QuickMatch(_)
+            | Self::GetGameState(_) => None,
+        }
+    }
+}
+
+/// Chosen by the client to match up a `Response` with the `Request` it answers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RequestId(pub u64);
+
+/// What a client sends to the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientMessage {
+    pub id: RequestId,
+    pub request: Request,
+}
+
+/// What the server sends to a client. The `id` is the one from the `ClientMessage` being answered,
+/// or `None` when the server is sending something unprompted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerMessage {
+    pub id: Option<RequestId>,
+    pub response: Response,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Response {
-    AddPlayer(PlayerId),
+    Hello {
+        version: u32,
+        capabilities: Vec<Capability>,
+    },
+    AddPlayer(PlayerId, PlayerToken),
     CreateGame(GameId),
+    CreatePrivateGame(GameId, JoinCode),
     JoinedGame(PlayerId, Player),
     Advance(Location, AttackResult),
     PlaceShip(ShipId, Location, Direction),
     WaitForTurn(Option<(Location, AttackResult)>, Vec<PlayerId>),
     Winner(Option<PlayerId>),
+    OpponentDisconnected(PlayerId),
     Error(super::Error),
+    /// Events describing the game as it stands, as if the subscriber had been there all along.
+    Subscribed(Vec<GameEvent>),
+    /// Pushed to subscribers, without a `RequestId`.
+    Event(GameEvent),
+    OpenGames(Vec<OpenGame>),
+    QuickMatch(PlayerId, PlayerToken),
+    ChatSent,
+    Rematch,
+    GameState(Box<GameState>),
+}
+
+/// The most characters a `ChatMessage::Text` may have.
+pub const MAX_CHAT_LENGTH: usize = 200;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChatMessage {
+    Text(String),
+    Emote(Emote),
+}
+
+impl fmt::Display for ChatMessage {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Text(text) => write!(fmt, "{}", text),
+            Self::Emote(emote) => write!(fmt, "{}", emote),
+        }
+    }
+}
+
+/// Canned messages, quicker to send than typing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Emote {
+    Hello,
+    GoodLuck,
+    NiceShot,
+    Oops,
+    GoodGame,
+}
+
+impl Emote {
+    pub const ALL: &'static [Self] = &[
+        Self::Hello,
+        Self::GoodLuck,
+        Self::NiceShot,
+        Self::Oops,
+        Self::GoodGame,
+    ];
+}
+
+impl fmt::Display for Emote {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Hello => write!(fmt, "Hello!"),
+            Self::GoodLuck => write!(fmt, "Good luck!"),
+            Self::NiceShot => write!(fmt, "Nice shot!"),
+            Self::Oops => write!(fmt, "Oops!"),
+            Self::GoodGame => write!(fmt, "Good game!"),
+        }
+    }
+}
+
+/// A public game with one player, waiting for another.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpenGame {
+    pub game_id: GameId,
+    /// The name of the player waiting.
+    pub host: String,
+    pub rules: Rules,
+}
+
+/// Something which happened in a game, as seen by one of its players.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GameEvent {
+    OpponentJoined(PlayerId, String),
+    /// The opponent has placed all their ships.
+    OpponentReady(PlayerId),
+    ShotFired {
+        attacker: PlayerId,
+        target: PlayerId,
+        location: Location,
+        result: AttackResult,
+    },
+    ShipSunk {
+        owner: PlayerId,
+        ship: String,
+    },
+    /// Everyone has joined, and the given player will shoot first once all ships are placed.
+    FirstTurn {
+        player: PlayerId,
+        chosen_by: FirstTurn,
+    },
+    /// It is now the given player's turn.
+    Turn(PlayerId),
+    GameOver {
+        winner: PlayerId,
+    },
+    OpponentDisconnected(PlayerId),
+    Chat {
+        from: PlayerId,
+        name: String,
+        message: ChatMessage,
+    },
+    /// The given player would like a rematch.
+    RematchOffered(PlayerId),
+    /// Everyone accepted a rematch, which is played as the given game. Pairs each player's id in
+    /// the old game with their id in the new one. Their tokens carry over.
+    Rematch {
+        game_id: GameId,
+        players: Vec<(PlayerId, PlayerId)>,
+    },
+    /// Sent when a game of a series starts, and again just before it ends.
+    Series(SeriesScore),
+}
+
+/// A player's view of their game, enough for a client to start over from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameState {
+    pub player_id: PlayerId,
+    /// The player's ships and both their fields.
+    pub player: Player,
+    pub phase: GamePhase,
+    /// Whose turn it is, once the game has started.
+    pub turn: Option<PlayerId>,
+    pub opponents: Vec<Opponent>,
+    pub series: Option<SeriesScore>,
+    /// How many shots have been fired so far. A client which counts the `GameEvent::ShotFired`
+    /// it gets from here on can tell how far along the game it is.
+    pub shots_fired: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Opponent {
+    pub player_id: PlayerId,
+    pub name: String,
+    /// Whether they have placed all their ships.
+    pub ready: bool,
+    /// Their ships which have been sunk, which are no secret anymore.
+    pub sunk: Vec<Ship>,
+}
+
+/// How a series of games is going.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeriesScore {
+    pub best_of: u32,
+    /// How many games each player has won.
+    pub wins: Vec<(PlayerId, u32)>,
+}
+
+impl SeriesScore {
+    /// The games won by the given player, and by everyone else.
+    pub fn score(&self, player_id: PlayerId) -> (u32, u32) {
+        let (ours, theirs): (Vec<_>, Vec<_>) =
+            self.wins.iter().partition(|&&(p, _)| p == player_id);
+        let sum = |wins: Vec<&(PlayerId, u32)>| wins.iter().map(|&&(_, w)| w).sum();
+        (sum(ours), sum(theirs))
+    }
 }
 
 impl From<super::Result<Self>> for Response {
@@ -32,3 +298,12 @@ impl From<super::Result<Self>> for Response {
         }
     }
 }
+
+#[test]
+fn test_unknown_capabilities() {
+    let hello = r#"{"Hello":{"version":3,"capabilities":["FromTheFuture"]}}"#;
+    match serde_json::from_str(hello).unwrap() {
+        Request::Hello { capabilities, .. } => assert_eq!(capabilities, [Capability::Unknown]),
+        r => panic!("unexpected request {:?}", r),
+    }
+}
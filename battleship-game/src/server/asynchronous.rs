@@ -0,0 +1,351 @@
+// copyright 2020 Remi Bernotavicius
+
+//! A server which runs on tokio. The `GameServer` is owned by a single task, and every connection
+//! gets a task of its own which sends it requests over a channel. Connections waiting for their
+//! turn only cost a parked task rather than a thread.
+
+use super::{
+    ConnectionId, GameServer, Outbound, Outbox, HANDSHAKE_TIMEOUT, TICK_INTERVAL, WRITE_TIMEOUT,
+};
+use crate::protocol::{ClientMessage, Response, ServerMessage};
+use crate::transport::{self, Codec};
+use futures_util::{sink, stream, Sink, SinkExt as _, Stream, StreamExt as _};
+use log::info;
+use std::collections::HashMap;
+use std::io;
+use std::time::Instant;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::tungstenite::Message;
+
+enum Command {
+    Connect(Peer, oneshot::Sender<crate::Result<ConnectionId>>),
+    Message(ConnectionId, ClientMessage),
+    Disconnect(ConnectionId),
+}
+
+/// How many commands may wait for the game task. Once it falls this far behind, connections stop
+/// reading until it catches up.
+const MAX_QUEUED_COMMANDS: usize = 256;
+
+/// Where the game task sends a connection's share of each `Outbox`. Holds at most
+/// `Limits::max_queued_writes` batches.
+type Peer = mpsc::Sender<Vec<Outbound>>;
+
+#[derive(Clone)]
+pub struct AsyncGameServer {
+    commands: mpsc::Sender<Command>,
+    max_message_size: usize,
+    max_queued_writes: usize,
+}
+
+impl AsyncGameServer {
+    /// Start the task which owns the game state. Must be called from within a tokio runtime.
+    pub fn spawn(game: GameServer) -> Self {
+        let (commands, receiver) = mpsc::channel(MAX_QUEUED_COMMANDS);
+        let limits = game.limits();
+        tokio::spawn(run_game(game, receiver));
+        Self {
+            commands,
+            max_message_size: limits.max_message_size,
+            max_queued_writes: limits.max_queued_writes,
+        }
+    }
+
+    pub async fn serve_tcp(&self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, address) = listener.accept().await?;
+            info!("received connection from {}", address);
+            tokio::spawn(self.clone().process_tcp(stream));
+        }
+    }
+
+    pub async fn serve_websocket(&self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, address) = listener.accept().await?;
+            info!("received websocket connection from {}", address);
+            tokio::spawn(self.clone().process_websocket(stream));
+        }
+    }
+
+    async fn process_tcp(self, stream: TcpStream) {
+        let (reader, writer) = stream.into_split();
+        let incoming = stream::unfold(reader, |mut reader| async move {
+            let mut data = vec![0; 4096];
+            match reader.read(&mut data).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    data.truncate(n);
+                    Some((Ok(data), reader))
+                }
+                Err(e) => Some((Err(e), reader)),
+            }
+        });
+        let outgoing = sink::unfold(writer, |mut writer, data: Vec<u8>| async move {
+            writer.write_all(&data).await?;
+            Ok::<_, io::Error>(writer)
+        });
+        self.process_requests(Box::pin(incoming), Box::pin(outgoing))
+            .await;
+    }
+
+    async fn process_websocket(self, stream: TcpStream) {
+        let handshake = tokio_tungstenite::accept_async(stream);
+        let socket = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+            Ok(Ok(socket)) => socket,
+            Ok(Err(e)) => {
+                info!("websocket handshake failed: {}", e);
+                return;
+            }
+            Err(_) => {
+                info!("websocket handshake timed out");
+                return;
+            }
+        };
+        let (outgoing, incoming) = socket.split();
+        let incoming = incoming.filter_map(|message| async move {
+            match message {
+                Ok(m) if m.is_binary() || m.is_text() => Some(Ok(m.into_data().to_vec())),
+                Ok(_) => None,
+                Err(e) => Some(Err(io::Error::other(e))),
+            }
+        });
+        let outgoing = outgoing
+            .with(|data: Vec<u8>| async move {
+                Ok::<_, tokio_tungstenite::tungstenite::Error>(Message::binary(data))
+            })
+            .sink_map_err(io::Error::other);
+        self.process_requests(Box::pin(incoming), Box::pin(outgoing))
+            .await;
+    }
+
+    /// Read frames from the connection and pass the requests they contain on to the game task,
+    /// while delivering whatever it sends back.
+    async fn process_requests<I, O>(self, mut incoming: I, mut outgoing: O)
+    where
+        I: Stream<Item = io::Result<Vec<u8>>> + Unpin,
+        O: Sink<Vec<u8>, Error = io::Error> + Unpin,
+    {
+        let (peer, mut outbound) = mpsc::channel(self.max_queued_writes);
+        let (reply, connection_id) = oneshot::channel();
+        if self
+            .commands
+            .send(Command::Connect(peer, reply))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        let connection_id = match connection_id.await {
+            Ok(Ok(connection_id)) => connection_id,
+            Ok(Err(e)) => {
+                info!("refusing connection: {}", e);
+                send(&mut outgoing, Codec::Json, unprompted(Response::Error(e)))
+                    .await
+                    .ok();
+                return;
+            }
+            Err(_) => return,
+        };
+
+        let mut codec = Codec::Json;
+        let mut greeted = false;
+        let mut buffer = vec![];
+        let handshake_deadline = tokio::time::Instant::now() + HANDSHAKE_TIMEOUT;
+        loop {
+            match transport::decode_frame(&mut buffer, self.max_message_size) {
+                Ok(Some(payload)) => {
+                    let message = match codec.decode(&payload) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            info!("received malformed message: {}", e);
+                            let response = Response::Error(crate::Error::MalformedMessage);
+                            send(&mut outgoing, codec, unprompted(response)).await.ok();
+                            continue;
+                        }
+                    };
+                    if self
+                        .commands
+                        .send(Command::Message(connection_id, message))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                    // Nothing after the `Hello` can be decoded until we know which codec to use.
+                    if !greeted {
+                        let batch = outbound.recv().await.unwrap_or_default();
+                        if batch.is_empty() || !deliver(&mut outgoing, &mut codec, batch).await {
+                            break;
+                        }
+                        greeted = true;
+                    }
+                    continue;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    let response = Response::Error(crate::Error::MessageTooLarge);
+                    send(&mut outgoing, codec, unprompted(response)).await.ok();
+                    info!("abandoning connection due to error: {}", e);
+                    break;
+                }
+            }
+
+            tokio::select! {
+                data = incoming.next() => match data {
+                    Some(Ok(data)) => buffer.extend(data),
+                    _ => break,
+                },
+                batch = outbound.recv() => match batch {
+                    Some(batch) => if !deliver(&mut outgoing, &mut codec, batch).await {
+                        break;
+                    },
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(handshake_deadline), if !greeted => {
+                    info!("abandoning connection which never said hello");
+                    break;
+                }
+            }
+        }
+        self.commands
+            .send(Command::Disconnect(connection_id))
+            .await
+            .ok();
+    }
+}
+
+fn unprompted(response: Response) -> ServerMessage {
+    ServerMessage { id: None, response }
+}
+
+async fn send<O>(outgoing: &mut O, codec: Codec, message: ServerMessage) -> transport::Result<()>
+where
+    O: Sink<Vec<u8>, Error = io::Error> + Unpin,
+{
+    let payload = codec.encode(&message)?;
+    let frame = transport::encode_frame(&payload);
+    tokio::time::timeout(WRITE_TIMEOUT, outgoing.send(frame))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    Ok(())
+}
+
+/// Carry out the game task's instructions for this connection. Returns false if the connection
+/// should be closed.
+async fn deliver<O>(outgoing: &mut O, codec: &mut Codec, batch: Vec<Outbound>) -> bool
+where
+    O: Sink<Vec<u8>, Error = io::Error> + Unpin,
+{
+    for outbound in batch {
+        match outbound {
+            Outbound::Send(message) => {
+                if send(outgoing, *codec, message).await.is_err() {
+                    return false;
+                }
+            }
+            Outbound::SetCodec(new_codec) => *codec = new_codec,
+            Outbound::Close => return false,
+        }
+    }
+    true
+}
+
+/// Hand each connection its share of the outbox. Connections which fall too far behind are closed.
+fn dispatch(game: &mut GameServer, peers: &mut HashMap<ConnectionId, Peer>, outbox: Outbox) {
+    let mut batches: HashMap<ConnectionId, Vec<Outbound>> = HashMap::new();
+    for (connection_id, outbound) in outbox {
+        batches.entry(connection_id).or_default().push(outbound);
+    }
+    let mut lagging = vec![];
+    for (connection_id, batch) in batches {
+        let closing = batch.iter().any(|o| matches!(o, Outbound::Close));
+        if let Some(peer) = peers.get(&connection_id) {
+            if peer.try_send(batch).is_err() {
+                lagging.push(connection_id);
+            }
+        }
+        if closing {
+            peers.remove(&connection_id);
+        }
+    }
+    for connection_id in lagging {
+        info!("closing connection which isn't keeping up");
+        peers.remove(&connection_id);
+        let outbox = game.disconnect(connection_id);
+        dispatch(game, peers, outbox);
+    }
+}
+
+async fn run_game(mut game: GameServer, mut commands: mpsc::Receiver<Command>) {
+    let mut peers = HashMap::new();
+    let mut ticks = tokio::time::interval(TICK_INTERVAL);
+    loop {
+        let outbox = tokio::select! {
+            command = commands.recv() => match command {
+                Some(Command::Connect(peer, reply)) => {
+                    let connection_id = match game.connect() {
+                        Ok(connection_id) => connection_id,
+                        Err(e) => {
+                            reply.send(Err(e)).ok();
+                            continue;
+                        }
+                    };
+                    peers.insert(connection_id, peer);
+                    if reply.send(Ok(connection_id)).is_ok() {
+                        continue;
+                    }
+                    peers.remove(&connection_id);
+                    game.disconnect(connection_id)
+                }
+                Some(Command::Message(connection_id, message)) => game.handle(connection_id, message),
+                Some(Command::Disconnect(connection_id)) => {
+                    peers.remove(&connection_id);
+                    game.disconnect(connection_id)
+                }
+                None => break,
+            },
+            _ = ticks.tick() => game.tick(Instant::now()),
+        };
+        dispatch(&mut game, &mut peers, outbox);
+    }
+}
+
+#[tokio::test]
+async fn test_tcp_round_trip() {
+    use crate::protocol::{self, Request, RequestId};
+    use crate::transport::FramedStream;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = AsyncGameServer::spawn(GameServer::new());
+    tokio::spawn(async move { server.serve_tcp(listener).await });
+
+    let conversation = tokio::task::spawn_blocking(move || {
+        let mut client = FramedStream::new(std::net::TcpStream::connect(address).unwrap());
+        let mut request = |id, request| {
+            client.send(&ClientMessage { id, request }).unwrap();
+            client.receive::<ServerMessage>().unwrap()
+        };
+        let hello = Request::Hello {
+            version: protocol::PROTOCOL_VERSION,
+            capabilities: vec![],
+        };
+        assert!(matches!(
+            request(RequestId(1), hello),
+            ServerMessage {
+                id: Some(RequestId(1)),
+                response: Response::Hello { .. }
+            }
+        ));
+        assert!(matches!(
+            request(RequestId(2), Request::CreateGame),
+            ServerMessage {
+                id: Some(RequestId(2)),
+                response: Response::CreateGame(_)
+            }
+        ));
+    });
+    conversation.await.unwrap();
+}
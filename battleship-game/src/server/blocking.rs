@@ -1,17 +1,25 @@
 // copyright 2020 Remi Bernotavicius
 
-use super::GameServer;
-use crate::protocol::Request;
+use super::{ConnectionId, GameServer, Outbound, Outbox, TICK_INTERVAL, WRITE_TIMEOUT};
+use crate::protocol::{Response, ServerMessage};
+use crate::transport::{self, Codec, FramedStream};
 use crossbeam_utils::thread;
 use log::info;
-use serde::Deserialize as _;
-use std::sync::Mutex;
+use std::collections::HashMap;
+use std::sync::{mpsc, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
 use std::{io, net};
 
+#[cfg(unix)]
+use std::os::unix::{
+    self,
+    net::{UnixListener, UnixStream},
+};
+
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
-    Serde(serde_json::Error),
+    Transport(transport::Error),
     Game(crate::Error),
 }
 
@@ -23,9 +31,9 @@ impl From<io::Error> for Error {
     }
 }
 
-impl From<serde_json::Error> for Error {
-    fn from(e: serde_json::Error) -> Self {
-        Self::Serde(e)
+impl From<transport::Error> for Error {
+    fn from(e: transport::Error) -> Self {
+        Self::Transport(e)
     }
 }
 
@@ -35,8 +43,58 @@ impl From<crate::Error> for Error {
     }
 }
 
+/// A connection which can be read on one thread while other threads write to it.
+pub trait SplitStream: Send {
+    type Reader: io::Read + Send;
+    type Writer: io::Write + Close + Send + 'static;
+
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)>;
+}
+
+/// Lets the writing half of a connection hang up on the whole of it, so that its reader stops
+/// waiting for more once the host is done with the connection.
+pub trait Close {
+    fn close(&self) -> io::Result<()>;
+}
+
+impl Close for net::TcpStream {
+    fn close(&self) -> io::Result<()> {
+        self.shutdown(net::Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl Close for UnixStream {
+    fn close(&self) -> io::Result<()> {
+        self.shutdown(net::Shutdown::Both)
+    }
+}
+
+impl SplitStream for net::TcpStream {
+    type Reader = net::TcpStream;
+    type Writer = net::TcpStream;
+
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+        let writer = self.try_clone()?;
+        writer.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        Ok((self, writer))
+    }
+}
+
+#[cfg(unix)]
+impl SplitStream for UnixStream {
+    type Reader = UnixStream;
+    type Writer = UnixStream;
+
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+        let writer = self.try_clone()?;
+        writer.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        Ok((self, writer))
+    }
+}
+
 pub trait Listener<'a>: Sync {
-    type Stream: io::Read + io::Write + Send;
+    type Stream: SplitStream;
     type Incoming: Iterator<Item = io::Result<Self::Stream>> + 'a;
 
     fn incoming(&'a self) -> Self::Incoming;
@@ -51,45 +109,413 @@ impl<'a> Listener<'a> for net::TcpListener {
     }
 }
 
+#[cfg(unix)]
+impl<'a> Listener<'a> for UnixListener {
+    type Incoming = unix::net::Incoming<'a>;
+    type Stream = UnixStream;
+
+    fn incoming(&'a self) -> Self::Incoming {
+        UnixListener::incoming(self)
+    }
+}
+
+/// Where the host queues a connection's share of each `Outbox`, for its writer thread to deliver.
+struct Peer {
+    /// Holds at most `Limits::max_queued_writes` batches.
+    sender: mpsc::SyncSender<Vec<Outbound>>,
+    /// What the connection's messages are decoded with, which can change when it is greeted.
+    codec: Codec,
+}
+
+struct Host {
+    game: GameServer,
+    peers: HashMap<ConnectionId, Peer>,
+}
+
+impl Host {
+    /// Queue each connection's messages without writing them, so a connection that is slow to read
+    /// can't hold everyone else up while we have the lock. Connections which fall too far behind,
+    /// or can't be written to at all, are closed.
+    fn dispatch(&mut self, outbox: Outbox) {
+        let mut batches: HashMap<ConnectionId, Vec<Outbound>> = HashMap::new();
+        for (connection_id, outbound) in outbox {
+            batches.entry(connection_id).or_default().push(outbound);
+        }
+        let mut lagging = vec![];
+        for (connection_id, batch) in batches {
+            let peer = match self.peers.get_mut(&connection_id) {
+                Some(peer) => peer,
+                None => continue,
+            };
+            let mut closing = false;
+            for outbound in &batch {
+                match outbound {
+                    Outbound::SetCodec(codec) => peer.codec = *codec,
+                    Outbound::Close => closing = true,
+                    Outbound::Send(_) => {}
+                }
+            }
+            if peer.sender.try_send(batch).is_err() {
+                lagging.push(connection_id);
+            } else if closing {
+                self.peers.remove(&connection_id);
+            }
+        }
+        for connection_id in lagging {
+            info!("closing connection which isn't keeping up");
+            self.peers.remove(&connection_id);
+            let outbox = self.game.disconnect(connection_id);
+            self.dispatch(outbox);
+        }
+    }
+}
+
+/// Deliver what the host queues for a connection, then hang up on it. Whether the host closed it,
+/// dropped it for falling behind or it can't be written to, nobody is left to answer its reader.
+fn write_outbound<W: io::Write + Close>(mut writer: W, batches: mpsc::Receiver<Vec<Outbound>>) {
+    deliver(&mut writer, batches);
+    writer.close().ok();
+}
+
+fn deliver<W: io::Write>(writer: W, batches: mpsc::Receiver<Vec<Outbound>>) {
+    let mut writer = FramedStream::new(writer);
+    for batch in batches {
+        for outbound in batch {
+            match outbound {
+                Outbound::Send(message) => {
+                    if let Err(e) = writer.send(&message) {
+                        info!("failed to write to connection: {}", e);
+                        return;
+                    }
+                }
+                Outbound::SetCodec(codec) => writer.set_codec(codec),
+                Outbound::Close => return,
+            }
+        }
+    }
+}
+
 pub struct BlockingGameServer {
-    game: Mutex<GameServer>,
+    host: Mutex<Host>,
 }
 
 impl BlockingGameServer {
     pub fn new() -> Self {
+        Self::with_game_server(GameServer::new())
+    }
+
+    pub fn with_game_server(game: GameServer) -> Self {
         Self {
-            game: Mutex::new(GameServer::new()),
+            host: Mutex::new(Host {
+                game,
+                peers: HashMap::new(),
+            }),
         }
     }
 
-    pub fn process_requests<S: io::Read + io::Write>(&self, mut conn: S) {
+    /// Keep serving everyone else, even if a thread panicked while holding the lock.
+    fn host(&self) -> MutexGuard<'_, Host> {
+        self.host.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn process_requests<S: SplitStream>(&self, conn: S) {
+        let (reader, writer) = match conn.split() {
+            Ok(halves) => halves,
+            Err(e) => {
+                info!("abandoning connection due to error: {}", e);
+                return;
+            }
+        };
+        let mut reader = FramedStream::new(reader);
+        let (connected, batches) = {
+            let mut host = self.host();
+            let limits = host.game.limits();
+            reader.set_max_frame_size(limits.max_message_size);
+            let (sender, batches) = mpsc::sync_channel(limits.max_queued_writes);
+            let connected = host.game.connect();
+            if let Ok(connection_id) = connected {
+                let codec = reader.codec();
+                host.peers.insert(connection_id, Peer { sender, codec });
+            }
+            (connected, batches)
+        };
+        let connection_id = match connected {
+            Ok(connection_id) => connection_id,
+            Err(e) => {
+                info!("refusing connection: {}", e);
+                let message = ServerMessage {
+                    id: None,
+                    response: Response::Error(e),
+                };
+                FramedStream::new(writer).send(&message).ok();
+                return;
+            }
+        };
+        let writer = std::thread::spawn(move || write_outbound(writer, batches));
+
         loop {
-            match Request::deserialize(&mut serde_json::Deserializer::from_reader(&mut conn)) {
-                Ok(request) => {
-                    let reader = self.game.lock().unwrap().handle_request(request);
-                    let response = reader.recv().unwrap();
-                    serde_json::to_writer(&mut conn, &response).ok();
+            let message = reader.receive();
+            let mut host = self.host();
+            match message {
+                Ok(message) => {
+                    let outbox = host.game.handle(connection_id, message);
+                    host.dispatch(outbox);
+                }
+                Err(e) if e.is_recoverable() => {
+                    info!("received malformed message: {}", e);
+                    let response = Response::Error(crate::Error::MalformedMessage);
+                    host.dispatch(vec![(connection_id, unprompted(response))]);
                 }
                 Err(e) => {
+                    if let transport::Error::FrameTooLarge(_) = e {
+                        let response = Response::Error(crate::Error::MessageTooLarge);
+                        host.dispatch(vec![(connection_id, unprompted(response))]);
+                    }
                     info!("abandoning connection due to error: {}", e);
                     break;
                 }
             }
+            match host.peers.get(&connection_id) {
+                Some(peer) => reader.set_codec(peer.codec),
+                None => break,
+            }
+        }
+
+        {
+            let mut host = self.host();
+            host.peers.remove(&connection_id);
+            let outbox = host.game.disconnect(connection_id);
+            host.dispatch(outbox);
+        }
+        writer.join().ok();
+    }
+
+    fn tick(&self) {
+        let mut host = self.host();
+        let outbox = host.game.tick(Instant::now());
+        host.dispatch(outbox);
+    }
+
+    fn tick_forever<'env>(&'env self, scope: &thread::Scope<'env>) {
+        scope.spawn(move |_| loop {
+            std::thread::sleep(TICK_INTERVAL);
+            self.tick();
+        });
+    }
+
+    fn accept<'env, 'a, L>(&'env self, scope: &thread::Scope<'env>, listener: &'a L)
+    where
+        L: Listener<'a>,
+        L::Stream: 'env,
+    {
+        for connection in listener.incoming() {
+            if let Ok(connection) = connection {
+                scope.spawn(move |_| {
+                    info!("received connection");
+                    self.process_requests(connection);
+                });
+            }
         }
     }
 
     pub fn run<'a, L: Listener<'a>>(&mut self, listener: &'a L) {
+        let this = &*self;
         thread::scope(|scope| {
-            for connection in listener.incoming() {
-                if let Ok(connection) = connection {
-                    let their_self = &self;
-                    scope.spawn(move |_| {
-                        info!("received connection");
-                        their_self.process_requests(connection);
-                    });
-                }
-            }
+            this.tick_forever(scope);
+            this.accept(scope, listener);
+        })
+        .unwrap();
+    }
+
+    /// Serve the players from both listeners, such as one for each transport, so they can play
+    /// each other.
+    pub fn run_both<'a, A: Listener<'a>, B: Listener<'a>>(&mut self, a: &'a A, b: &'a B) {
+        let this = &*self;
+        thread::scope(|scope| {
+            this.tick_forever(scope);
+            scope.spawn(move |scope| this.accept(scope, a));
+            this.accept(scope, b);
         })
         .unwrap();
     }
 }
+
+fn unprompted(response: Response) -> Outbound {
+    Outbound::Send(ServerMessage { id: None, response })
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unix_socket() {
+    use crate::client::blocking::BlockingGameClient;
+
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("battleship.sock");
+    let listener = UnixListener::bind(&path).unwrap();
+    let server = BlockingGameServer::new();
+    thread::scope(|scope| {
+        scope.spawn(|_| {
+            let connection = listener.incoming().next().unwrap().unwrap();
+            server.process_requests(connection);
+        });
+
+        let mut client = BlockingGameClient::connect(UnixStream::connect(&path).unwrap()).unwrap();
+        client.create_game("a").unwrap();
+        assert_eq!(client.game_state().unwrap().player.name(), "a");
+    })
+    .unwrap();
+}
+
+#[test]
+fn test_silent_connection_is_hung_up_on() {
+    use super::HANDSHAKE_TIMEOUT;
+    use std::io::Read as _;
+
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let server = BlockingGameServer::new();
+    thread::scope(|scope| {
+        let connection = listener.incoming().next().unwrap().unwrap();
+        let server = &server;
+        let connection = scope.spawn(move |_| server.process_requests(connection));
+        while server.host().peers.is_empty() {
+            std::thread::yield_now();
+        }
+
+        let mut host = server.host();
+        let outbox = host.game.tick(Instant::now() + HANDSHAKE_TIMEOUT);
+        host.dispatch(outbox);
+        drop(host);
+
+        // The thread reading from it is let go too, rather than waiting on it forever.
+        connection.join().unwrap();
+        assert_eq!(client.read(&mut [0]).unwrap(), 0);
+    })
+    .unwrap();
+}
+
+/// A connection whose peer never reads, so writing to it blocks until the test lets go.
+#[cfg(test)]
+struct StalledStream<R> {
+    reader: R,
+    writing: mpsc::Sender<()>,
+    release: mpsc::Receiver<()>,
+}
+
+#[cfg(test)]
+struct StalledWriter {
+    writing: mpsc::Sender<()>,
+    release: mpsc::Receiver<()>,
+}
+
+#[cfg(test)]
+impl Close for StalledWriter {
+    fn close(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl io::Write for StalledWriter {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        self.writing.send(()).ok();
+        self.release.recv().ok();
+        Err(io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl<R: io::Read + Send> SplitStream for StalledStream<R> {
+    type Reader = R;
+    type Writer = StalledWriter;
+
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+        let writer = StalledWriter {
+            writing: self.writing,
+            release: self.release,
+        };
+        Ok((self.reader, writer))
+    }
+}
+
+#[test]
+fn test_stalled_reader_does_not_block_others() {
+    use crate::client::{blocking::BlockingGameClient, GameClient};
+    use crate::testkit::pipe;
+
+    let server = BlockingGameServer::new();
+    thread::scope(|scope| {
+        let (stalled, server_end) = pipe();
+        let (reader, _) = server_end.split().unwrap();
+        let (writing, started) = mpsc::channel();
+        let (release, released) = mpsc::channel();
+        let stream = StalledStream {
+            reader,
+            writing,
+            release: released,
+        };
+        let server = &server;
+        scope.spawn(move |_| server.process_requests(stream));
+        let mut stalled = FramedStream::new(stalled);
+        stalled.send(&GameClient::new().hello()).unwrap();
+        started.recv().unwrap();
+
+        let (client, server_end) = pipe();
+        scope.spawn(move |_| server.process_requests(server_end));
+        let mut client = BlockingGameClient::connect(client).unwrap();
+        client.create_game("a").unwrap();
+        drop(client);
+
+        drop(release);
+        drop(stalled);
+    })
+    .unwrap();
+}
+
+#[test]
+fn test_connection_that_falls_behind_is_closed() {
+    use super::Limits;
+    use crate::client::GameClient;
+    use crate::testkit::pipe;
+
+    let mut game = GameServer::new();
+    game.set_limits(Limits {
+        max_queued_writes: 1,
+        ..Limits::default()
+    });
+    let server = BlockingGameServer::with_game_server(game);
+    thread::scope(|scope| {
+        let (stalled, server_end) = pipe();
+        let (reader, _) = server_end.split().unwrap();
+        let (writing, started) = mpsc::channel();
+        let (release, released) = mpsc::channel();
+        let stream = StalledStream {
+            reader,
+            writing,
+            release: released,
+        };
+        let server = &server;
+        let connection = scope.spawn(move |_| server.process_requests(stream));
+        let mut client = GameClient::new();
+        let mut stalled = FramedStream::new(stalled);
+        stalled.send(&client.hello()).unwrap();
+        started.recv().unwrap();
+
+        // The first answer waits behind the greeting being written, and the second doesn't fit.
+        stalled.set_codec(Codec::Bincode);
+        stalled.send(&client.list_open_games()).unwrap();
+        stalled.send(&client.list_open_games()).unwrap();
+        while !server.host().peers.is_empty() {
+            std::thread::yield_now();
+        }
+        assert!(server.host().game.connections.is_empty());
+
+        drop(release);
+        connection.join().unwrap();
+    })
+    .unwrap();
+}
@@ -1,45 +1,672 @@
 // copyright 2020 Remi Bernotavicius
-use super::protocol::{Request, Response};
+
+//! `GameServer` is a state machine which does no IO of its own. A host accepts connections,
+//! decodes their messages and feeds them to the server, then carries out the `Outbound` actions it
+//! gets back, which may be addressed to any connection.
+
+use super::protocol::{
+    self, ChatMessage, ClientMessage, GameEvent, GameState, OpenGame, Opponent, Request, RequestId,
+    Response, SeriesScore, ServerMessage,
+};
+use super::transport::{Codec, DEFAULT_MAX_FRAME_SIZE};
 use super::{
-    AttackResult, Direction, Error, Game, GameId, Location, Play as _, Player, PlayerId, Result,
-    ShipId,
+    AttackResult, Direction, Error, Game, GameId, GameOptions, GameRemoval, JoinCode, Location,
+    Play as _, Player, PlayerId, PlayerToken, Result, Rules, ShipId,
 };
-use log::info;
-use std::collections::HashMap;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use log::{error, info};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::time::{Duration, Instant};
+use storage::{GameRecord, Storage};
 
+#[cfg(feature = "async-server")]
+pub mod asynchronous;
 pub mod blocking;
+pub mod storage;
+
+/// How often hosts should call `GameServer::tick`.
+pub const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long hosts let a write to a connection take before giving up on it.
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a new connection has to send its `Hello` before it is closed.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A player may send this many chat messages within `CHAT_WINDOW`.
+const CHAT_BURST: usize = 5;
+const CHAT_WINDOW: Duration = Duration::from_secs(10);
+
+/// The window `Limits::max_requests_per_second` is counted over.
+const REQUEST_WINDOW: Duration = Duration::from_secs(1);
+
+/// The most open games one `ListOpenGames` reply lists, so that it fits in a frame.
+const MAX_OPEN_GAMES_LISTED: usize = 100;
+
+/// How often the storage is given a snapshot of every game.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Answer the `Request::Hello` which every connection must start with. Clients newer than us are
+/// told to speak our version instead.
+pub fn handshake(request: &Request) -> Response {
+    match *request {
+        Request::Hello { version, .. } if version >= protocol::MIN_PROTOCOL_VERSION => {
+            Response::Hello {
+                version: version.min(protocol::PROTOCOL_VERSION),
+                capabilities: protocol::CAPABILITIES.to_vec(),
+            }
+        }
+        Request::Hello { version, .. } => {
+            Response::Error(Error::UnsupportedProtocolVersion(version))
+        }
+        _ => Response::Error(Error::HandshakeRequired),
+    }
+}
+
+/// Identifies a connection to a `GameServer`, handed out by `GameServer::connect`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId(u64);
+
+/// Something the host has to do with a connection, in order.
+#[derive(Debug)]
+pub enum Outbound {
+    Send(ServerMessage),
+    /// Use this codec for everything sent or received from now on.
+    SetCodec(Codec),
+    /// Close the connection. The server has already forgotten about it.
+    Close,
+}
+
+pub type Outbox = Vec<(ConnectionId, Outbound)>;
+
+/// How long games are kept before they are removed.
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    /// Games nobody joined after whoever created them.
+    pub abandoned: Duration,
+    /// Games somebody won.
+    pub finished: Duration,
+    /// Games nobody made a move in.
+    pub idle: Duration,
+    /// How long to remember why a game was removed, to tell its players.
+    pub tombstone: Duration,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            abandoned: Duration::from_secs(10 * 60),
+            finished: Duration::from_secs(60 * 60),
+            idle: Duration::from_secs(24 * 60 * 60),
+            tombstone: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// How much any one client may ask of the server.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// The largest message hosts accept, in bytes.
+    pub max_message_size: usize,
+    /// The most characters a player's name may have.
+    pub max_name_length: usize,
+    /// How many of the games a connection created may be around at once.
+    pub max_games_per_connection: usize,
+    /// How many requests a connection may send each second, as measured by `tick`.
+    pub max_requests_per_second: usize,
+    /// How many connections are served at once.
+    pub max_connections: usize,
+    /// How many batches of messages hosts queue for a connection that isn't reading them, before
+    /// closing it.
+    pub max_queued_writes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_message_size: DEFAULT_MAX_FRAME_SIZE,
+            max_name_length: 32,
+            max_games_per_connection: 10,
+            max_requests_per_second: 20,
+            max_connections: 1000,
+            max_queued_writes: 64,
+        }
+    }
+}
+
+/// How many games were removed for each reason.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RemovedGames {
+    pub abandoned: u64,
+    pub finished: u64,
+    pub idle: u64,
+}
+
+impl RemovedGames {
+    fn count(&mut self, removal: GameRemoval) {
+        match removal {
+            GameRemoval::Abandoned => self.abandoned += 1,
+            GameRemoval::Finished => self.finished += 1,
+            GameRemoval::Idle => self.idle += 1,
+        }
+    }
+}
+
+struct Activity {
+    created: Instant,
+    last_change: Instant,
+}
+
+struct Connection {
+    connected: Instant,
+    /// `None` until the handshake is complete.
+    codec: Option<Codec>,
+    /// The players this connection created or rejoined, and so may act on behalf of.
+    players: HashSet<PlayerId>,
+    /// The games this connection created.
+    games: HashSet<GameId>,
+    /// When the connection sent its most recent requests, oldest first.
+    recent_requests: VecDeque<Instant>,
+}
 
 pub struct GameServer {
     games: HashMap<GameId, Game>,
-    waiters: HashMap<PlayerId, Sender<Response>>,
-    last_attack_result: Option<(Location, AttackResult)>,
+    join_codes: HashMap<JoinCode, GameId>,
+    last_game_id: GameId,
+    activity: HashMap<GameId, Activity>,
+    retention: Retention,
+    limits: Limits,
+    /// Games which were removed, why, and when.
+    tombstones: HashMap<GameId, (GameRemoval, Instant)>,
+    removed: RemovedGames,
+    tokens: HashMap<PlayerToken, PlayerId>,
+    /// Every `WaitForTurn` still to be answered, for each player.
+    waiters: HashMap<PlayerId, Vec<(ConnectionId, RequestId)>>,
+    subscribers: HashMap<PlayerId, Vec<ConnectionId>>,
+    /// Events waiting to be sent, along with the game and the player they shouldn't go to.
+    events: Vec<(GameId, Option<PlayerId>, GameEvent)>,
+    connections: HashMap<ConnectionId, Connection>,
+    next_connection_id: u64,
+    disconnected: HashMap<PlayerId, Instant>,
+    /// When each player sent their most recent chat messages, oldest first.
+    recent_chat: HashMap<PlayerId, VecDeque<Instant>>,
+    /// The players of each finished game who asked for a rematch.
+    rematch_offers: HashMap<GameId, HashSet<PlayerId>>,
+    /// The shot each game's current player has yet to be told about, for `WaitForTurn`.
+    last_attack_results: HashMap<GameId, (Location, AttackResult)>,
+    now: Instant,
+    outbox: Outbox,
+    storage: Option<Box<dyn Storage>>,
+    /// Games changed since they were last saved. They are saved together on the next tick.
+    unsaved: HashSet<GameId>,
+    last_snapshot: Instant,
 }
 
 impl GameServer {
     pub fn new() -> Self {
         Self {
             games: HashMap::new(),
+            join_codes: HashMap::new(),
+            last_game_id: GameId(0),
+            activity: HashMap::new(),
+            retention: Retention::default(),
+            limits: Limits::default(),
+            tombstones: HashMap::new(),
+            removed: RemovedGames::default(),
+            tokens: HashMap::new(),
             waiters: HashMap::new(),
-            last_attack_result: None,
+            subscribers: HashMap::new(),
+            events: vec![],
+            connections: HashMap::new(),
+            next_connection_id: 0,
+            disconnected: HashMap::new(),
+            recent_chat: HashMap::new(),
+            rematch_offers: HashMap::new(),
+            last_attack_results: HashMap::new(),
+            now: Instant::now(),
+            outbox: vec![],
+            storage: None,
+            unsaved: HashSet::new(),
+            last_snapshot: Instant::now(),
         }
     }
 
+    /// A server which keeps its games in the given storage, starting with the ones already there.
+    pub fn with_storage(mut storage: Box<dyn Storage>) -> io::Result<Self> {
+        let mut server = Self::new();
+        for record in storage.load()? {
+            let game_id = record.game.id();
+            server.tokens.extend(record.tokens);
+            if let Some(join_code) = record.game.join_code() {
+                server.join_codes.insert(join_code.clone(), game_id);
+            }
+            server.games.insert(game_id, record.game);
+            server.touch(game_id);
+        }
+        server.last_game_id = storage.last_game_id()?;
+        info!("loaded {} games", server.games.len());
+        server.storage = Some(storage);
+        Ok(server)
+    }
+
+    fn record(&self, game_id: GameId) -> Option<GameRecord> {
+        let game = self.games.get(&game_id)?.clone();
+        let tokens = self
+            .tokens
+            .iter()
+            .filter(|(_, player_id)| player_id.game_id() == game_id)
+            .map(|(token, &player_id)| (token.clone(), player_id))
+            .collect();
+        Some(GameRecord { game, tokens })
+    }
+
+    fn save_games(&mut self) {
+        let unsaved = std::mem::take(&mut self.unsaved);
+        let records: Vec<_> = unsaved
+            .into_iter()
+            .filter_map(|id| self.record(id))
+            .collect();
+        if let (Some(storage), false) = (&mut self.storage, records.is_empty()) {
+            if let Err(e) = storage.save(&records) {
+                error!("failed to save {} games: {}", records.len(), e);
+            }
+        }
+    }
+
+    fn snapshot(&mut self) {
+        let mut game_ids: Vec<_> = self.games.keys().cloned().collect();
+        game_ids.sort();
+        let records: Vec<_> = game_ids
+            .into_iter()
+            .filter_map(|id| self.record(id))
+            .collect();
+        if let Some(storage) = &mut self.storage {
+            if let Err(e) = storage.snapshot(&records, self.last_game_id) {
+                error!("failed to take snapshot: {}", e);
+            }
+        }
+    }
+
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// How many games have been removed since the server started.
+    pub fn removed_games(&self) -> RemovedGames {
+        self.removed
+    }
+
     fn game(&mut self, game_id: GameId) -> Result<&mut Game> {
+        let tombstones = &self.tombstones;
         self.games
             .get_mut(&game_id)
-            .ok_or(Error::UnknownGame(game_id))
+            .ok_or_else(|| match tombstones.get(&game_id) {
+                Some((removal, _)) => Error::GameRemoved(game_id, *removal),
+                None => Error::UnknownGame(game_id),
+            })
     }
 
+    /// Note that the game changed, so it gets saved and isn't considered idle.
+    fn touch(&mut self, game_id: GameId) {
+        let now = self.now;
+        let activity = self.activity.entry(game_id).or_insert(Activity {
+            created: now,
+            last_change: now,
+        });
+        activity.last_change = now;
+        self.unsaved.insert(game_id);
+    }
+
+    #[cfg(test)]
     fn create_game(&mut self) -> GameId {
-        let max_id = self.games.keys().cloned().max().unwrap_or(GameId(0));
-        let id = max_id.incr();
+        let id = self.next_game_id();
         self.games.insert(id, Game::new(id));
+        self.touch(id);
         id
     }
 
-    fn add_player(&mut self, game_id: GameId, name: &str) -> Result<PlayerId> {
-        self.game(game_id)?.add_player(&name)
+    /// Create a game on behalf of the connection, unless it already has too many.
+    fn create_game_for(
+        &mut self,
+        connection_id: ConnectionId,
+        options: &GameOptions,
+    ) -> Result<(GameId, Option<JoinCode>)> {
+        let max = self.limits.max_games_per_connection;
+        let games = &self.games;
+        if let Some(connection) = self.connections.get_mut(&connection_id) {
+            connection
+                .games
+                .retain(|game_id| games.contains_key(game_id));
+            if connection.games.len() >= max {
+                return Err(Error::TooManyGames(max));
+            }
+        }
+        let (game_id, join_code) = self.create_custom_game(options)?;
+        if let Some(connection) = self.connections.get_mut(&connection_id) {
+            connection.games.insert(game_id);
+        }
+        Ok((game_id, join_code))
+    }
+
+    fn create_custom_game(&mut self, options: &GameOptions) -> Result<(GameId, Option<JoinCode>)> {
+        let id = self.next_game_id();
+        let (mut game, join_code) = if options.private {
+            loop {
+                let (game, join_code) = Game::new_private(id);
+                if !self.join_codes.contains_key(&join_code) {
+                    break (game, Some(join_code));
+                }
+            }
+        } else {
+            (Game::new(id), None)
+        };
+        game.set_best_of(options.best_of)?;
+        game.set_first_turn(options.first_turn);
+
+        if let Some(join_code) = &join_code {
+            self.join_codes.insert(join_code.clone(), id);
+        }
+        self.games.insert(id, game);
+        self.touch(id);
+        Ok((id, join_code))
+    }
+
+    fn next_game_id(&mut self) -> GameId {
+        // Ids of removed games aren't handed out again, lest their players end up in a new game.
+        self.last_game_id = self.last_game_id.incr();
+        self.last_game_id
+    }
+
+    /// Why the game should be removed, if it should.
+    fn removal(&self, game: &Game, activity: &Activity) -> Option<GameRemoval> {
+        let retention = &self.retention;
+        let since = |instant: Instant| self.now.saturating_duration_since(instant);
+        if game.is_over() && since(activity.last_change) >= retention.finished {
+            Some(GameRemoval::Finished)
+        } else if game.get_players().len() < 2 && since(activity.created) >= retention.abandoned {
+            Some(GameRemoval::Abandoned)
+        } else if since(activity.last_change) >= retention.idle {
+            Some(GameRemoval::Idle)
+        } else {
+            None
+        }
+    }
+
+    /// Remove the games which have been kept long enough, and forget about old tombstones.
+    fn reap(&mut self) {
+        let mut removals: Vec<_> = self
+            .games
+            .iter()
+            .filter_map(|(&game_id, game)| {
+                let activity = self.activity.get(&game_id)?;
+                Some((game_id, self.removal(game, activity)?))
+            })
+            .collect();
+        removals.sort_by_key(|&(game_id, _)| game_id);
+        for (game_id, removal) in removals {
+            self.remove_game(game_id, removal);
+        }
+
+        let (now, keep) = (self.now, self.retention.tombstone);
+        self.tombstones
+            .retain(|_, &mut (_, removed)| now.saturating_duration_since(removed) < keep);
+        let (games, tombstones) = (&self.games, &self.tombstones);
+        self.tokens.retain(|_, player_id| {
+            let game_id = player_id.game_id();
+            games.contains_key(&game_id) || tombstones.contains_key(&game_id)
+        });
+    }
+
+    fn remove_game(&mut self, game_id: GameId, removal: GameRemoval) {
+        let game = match self.games.remove(&game_id) {
+            Some(game) => game,
+            None => return,
+        };
+        if let Some(join_code) = game.join_code() {
+            self.join_codes.remove(join_code);
+        }
+        info!("removing game {}, {}", game_id, removal);
+        self.activity.remove(&game_id);
+        self.unsaved.remove(&game_id);
+        self.rematch_offers.remove(&game_id);
+        self.last_attack_results.remove(&game_id);
+        self.tombstones.insert(game_id, (removal, self.now));
+        self.removed.count(removal);
+        if let Some(storage) = &mut self.storage {
+            if let Err(e) = storage.remove(game_id) {
+                error!("failed to remove game {}: {}", game_id, e);
+            }
+        }
+
+        // The players' tokens are kept along with the tombstone, so they are told why.
+        let error = Error::GameRemoved(game_id, removal);
+        for player_id in game.get_players() {
+            for (connection_id, id) in self.waiters.remove(&player_id).unwrap_or_default() {
+                self.send(connection_id, Some(id), Response::Error(error.clone()));
+            }
+            for connection_id in self.subscribers.remove(&player_id).unwrap_or_default() {
+                self.send(connection_id, None, Response::Error(error.clone()));
+            }
+            self.disconnected.remove(&player_id);
+            self.recent_chat.remove(&player_id);
+        }
+    }
+
+    pub fn authenticate(&self, token: &PlayerToken) -> Result<PlayerId> {
+        self.tokens
+            .get(token)
+            .cloned()
+            .ok_or(Error::InvalidPlayerToken)
+    }
+
+    /// Authenticate a request on the given connection. Only players the connection created or
+    /// rejoined may be acted on behalf of.
+    fn authorize(&self, connection_id: ConnectionId, token: &PlayerToken) -> Result<PlayerId> {
+        let player_id = self.authenticate(token)?;
+        self.require_bound(connection_id, player_id)?;
+        Ok(player_id)
+    }
+
+    /// Fail unless the player was added or rejoined on the given connection.
+    fn require_bound(&self, connection_id: ConnectionId, player_id: PlayerId) -> Result<()> {
+        match self.connections.get(&connection_id) {
+            Some(connection) if connection.players.contains(&player_id) => Ok(()),
+            _ => Err(Error::PlayerNotOnConnection(player_id)),
+        }
+    }
+
+    fn add_player(&mut self, game_id: GameId, name: &str) -> Result<(PlayerId, PlayerToken)> {
+        if self.game(game_id)?.join_code().is_some() {
+            return Err(Error::JoinCodeRequired(game_id));
+        }
+        self.add_player_unchecked(game_id, name)
+    }
+
+    fn join_with_code(
+        &mut self,
+        join_code: &JoinCode,
+        name: &str,
+    ) -> Result<(PlayerId, PlayerToken)> {
+        let game_id = *self
+            .join_codes
+            .get(join_code)
+            .ok_or(Error::UnknownJoinCode)?;
+        self.add_player_unchecked(game_id, name)
+    }
+
+    fn check_name(&self, name: &str) -> Result<()> {
+        let max = self.limits.max_name_length;
+        if name.chars().count() > max {
+            return Err(Error::NameTooLong(max));
+        }
+        Ok(())
+    }
+
+    fn add_player_unchecked(
+        &mut self,
+        game_id: GameId,
+        name: &str,
+    ) -> Result<(PlayerId, PlayerToken)> {
+        self.check_name(name)?;
+        let game = self.game(game_id)?;
+        let player_id = game.add_player(name)?;
+        let first_turn = game.first_turn();
+        let token = PlayerToken::random();
+        self.tokens.insert(token.clone(), player_id);
+        self.touch(game_id);
+        let event = GameEvent::OpponentJoined(player_id, name.into());
+        self.publish(game_id, Some(player_id), event);
+        if let Some((player, chosen_by)) = first_turn {
+            self.publish(game_id, None, GameEvent::FirstTurn { player, chosen_by });
+        }
+        Ok((player_id, token))
+    }
+
+    /// Games with a single player, longest waiting first.
+    fn open_games(&self) -> Vec<OpenGame> {
+        let mut open_games: Vec<_> = self
+            .games
+            .values()
+            .filter(|game| game.join_code().is_none())
+            .filter_map(|game| match &game.get_players()[..] {
+                [host] => {
+                    let host = game.get_player(*host).ok()?;
+                    Some(OpenGame {
+                        game_id: game.id(),
+                        host: host.name().into(),
+                        rules: Rules {
+                            best_of: game.best_of(),
+                            ..host.rules()
+                        },
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+        open_games.sort_by_key(|g| g.game_id);
+        open_games
+    }
+
+    fn quick_match(
+        &mut self,
+        connection_id: ConnectionId,
+        name: &str,
+    ) -> Result<(PlayerId, PlayerToken)> {
+        self.check_name(name)?;
+        // Nobody is matched against themselves, in a game they're already playing.
+        let open_game = self
+            .open_games()
+            .into_iter()
+            .map(|open_game| open_game.game_id)
+            .find(|game_id| !self.is_playing(connection_id, *game_id));
+        let game_id = match open_game {
+            Some(game_id) => game_id,
+            None => {
+                self.create_game_for(connection_id, &GameOptions::default())?
+                    .0
+            }
+        };
+        self.add_player(game_id, name)
+    }
+
+    fn chat(&mut self, player_id: PlayerId, message: ChatMessage) -> Result<()> {
+        if let ChatMessage::Text(text) = &message {
+            if text.trim().is_empty() {
+                return Err(Error::EmptyChatMessage);
+            }
+            if text.chars().count() > protocol::MAX_CHAT_LENGTH {
+                return Err(Error::ChatTooLong(protocol::MAX_CHAT_LENGTH));
+            }
+        }
+        let game_id = player_id.game_id();
+        let name = self.game(game_id)?.get_player(player_id)?.name().to_owned();
+
+        let recent = self.recent_chat.entry(player_id).or_default();
+        if !within_rate(recent, self.now, CHAT_WINDOW, CHAT_BURST) {
+            return Err(Error::ChatRateLimited);
+        }
+
+        let event = GameEvent::Chat {
+            from: player_id,
+            name,
+            message,
+        };
+        self.publish(game_id, Some(player_id), event);
+        Ok(())
+    }
+
+    /// Once every player has asked for a rematch, move them all to a new game with the same rules.
+    fn rematch(&mut self, player_id: PlayerId) -> Result<()> {
+        let game_id = player_id.game_id();
+        let game = self.game(game_id)?;
+        if !game.is_over() {
+            return Err(Error::GameNotOver);
+        }
+        let players = game.get_players().len();
+        let offers = self.rematch_offers.entry(game_id).or_default();
+        offers.insert(player_id);
+        if offers.len() < players {
+            self.publish(
+                game_id,
+                Some(player_id),
+                GameEvent::RematchOffered(player_id),
+            );
+            return Ok(());
+        }
+        self.rematch_offers.remove(&game_id);
+
+        let new_game_id = self.next_game_id();
+        let (game, seats) = self.game(game_id)?.rematch(new_game_id)?;
+        let series = series_score(&game);
+        let first_turn = game.first_turn();
+        self.games.insert(new_game_id, game);
+        self.touch(new_game_id);
+        self.touch(game_id);
+        info!("game {} is a rematch of {}", new_game_id, game_id);
+
+        for &(old, new) in &seats {
+            for player_id in self.tokens.values_mut().filter(|p| **p == old) {
+                *player_id = new;
+            }
+            for connection in self.connections.values_mut() {
+                if connection.players.remove(&old) {
+                    connection.players.insert(new);
+                }
+            }
+            if let Some(subscribers) = self.subscribers.remove(&old) {
+                self.subscribers.insert(new, subscribers);
+            }
+            if let Some(since) = self.disconnected.remove(&old) {
+                self.disconnected.insert(new, since);
+            }
+            if let Some(recent) = self.recent_chat.remove(&old) {
+                self.recent_chat.insert(new, recent);
+            }
+        }
+        let event = GameEvent::Rematch {
+            game_id: new_game_id,
+            players: seats,
+        };
+        self.publish(new_game_id, None, event);
+        if let Some(series) = series {
+            self.publish(new_game_id, None, GameEvent::Series(series));
+        }
+        if let Some((player, chosen_by)) = first_turn {
+            self.publish(
+                new_game_id,
+                None,
+                GameEvent::FirstTurn { player, chosen_by },
+            );
+        }
+        Ok(())
     }
 
     fn place_ship(
@@ -49,8 +676,25 @@ impl GameServer {
         location: Location,
         direction: Direction,
     ) -> Result<()> {
-        self.game(player_id.game_id())?
-            .place_ship(player_id, ship_id, location, direction)
+        let game_id = player_id.game_id();
+        let game = self.game(game_id)?;
+        let was_ready = game.get_player(player_id)?.ships_placed();
+        game.place_ship(player_id, ship_id, location, direction)?;
+        let is_ready = game.get_player(player_id)?.ships_placed();
+        let turn = game.current_turn();
+        self.touch(game_id);
+
+        if !was_ready && is_ready {
+            self.publish(
+                game_id,
+                Some(player_id),
+                GameEvent::OpponentReady(player_id),
+            );
+            if let Some(turn) = turn {
+                self.publish(game_id, None, GameEvent::Turn(turn));
+            }
+        }
+        Ok(())
     }
 
     fn advance(
@@ -64,7 +708,33 @@ impl GameServer {
             .advance(player_a_id, player_b_id, location);
 
         if let Ok(result) = &result {
-            self.last_attack_result = Some((location, result.clone()));
+            let game_id = player_a_id.game_id();
+            self.last_attack_results
+                .insert(game_id, (location, result.clone()));
+            self.touch(game_id);
+            let event = GameEvent::ShotFired {
+                attacker: player_a_id,
+                target: player_b_id,
+                location,
+                result: result.clone(),
+            };
+            self.publish(game_id, None, event);
+            if let AttackResult::Sunk(ship) = result {
+                let owner = player_b_id;
+                let ship = ship.clone();
+                self.publish(game_id, None, GameEvent::ShipSunk { owner, ship });
+            }
+            let game = self.game(game_id)?;
+            match (game.winner(), game.current_turn()) {
+                (Some(winner), _) => {
+                    if let Some(series) = series_score(game) {
+                        self.publish(game_id, None, GameEvent::Series(series));
+                    }
+                    self.publish(game_id, None, GameEvent::GameOver { winner })
+                }
+                (None, Some(turn)) => self.publish(game_id, None, GameEvent::Turn(turn)),
+                (None, None) => {}
+            }
         }
 
         result
@@ -74,10 +744,8 @@ impl GameServer {
         if Some(player_id) == self.game(player_id.game_id())?.current_turn() {
             let players = self.game(player_id.game_id())?.get_players();
             let players = players.into_iter().filter(|&p| p != player_id).collect();
-            Ok(Some(Response::WaitForTurn(
-                self.last_attack_result.take(),
-                players,
-            )))
+            let last_attack_result = self.last_attack_results.remove(&player_id.game_id());
+            Ok(Some(Response::WaitForTurn(last_attack_result, players)))
         } else {
             Ok(None)
         }
@@ -90,62 +758,1437 @@ impl GameServer {
             .clone())
     }
 
+    fn game_state(&mut self, player_id: PlayerId) -> Result<GameState> {
+        let game = self.game(player_id.game_id())?;
+        let mut opponents = vec![];
+        for other in game.get_players().into_iter().filter(|&p| p != player_id) {
+            let opponent = game.get_player(other)?;
+            let mut sunk: Vec<_> = opponent
+                .ships()
+                .into_iter()
+                .filter(|(_, ship)| ship.sunk())
+                .collect();
+            sunk.sort_by_key(|&(ship_id, _)| ship_id);
+            opponents.push(Opponent {
+                player_id: other,
+                name: opponent.name().into(),
+                ready: opponent.ships_placed(),
+                sunk: sunk.into_iter().map(|(_, ship)| ship).collect(),
+            });
+        }
+        opponents.sort_by_key(|o| o.player_id);
+        Ok(GameState {
+            player_id,
+            player: game.get_player(player_id)?.clone(),
+            phase: game.phase(),
+            turn: game.current_turn(),
+            opponents,
+            series: series_score(game),
+            shots_fired: game.shots_fired(),
+        })
+    }
+
     fn check_waiters(&mut self) {
-        let game_ids: Vec<_> = self.games.keys().cloned().collect();
-        for game_id in game_ids {
-            if let Some(player_id) = self.game(game_id).unwrap().current_turn() {
-                if let Some(sender) = self.waiters.remove(&player_id) {
-                    let players = self.game(game_id).unwrap().get_players();
-                    let players = players.into_iter().filter(|&p| p != player_id).collect();
-                    let response = Response::WaitForTurn(self.last_attack_result.take(), players);
-                    info!("{:#?}", &response);
-                    sender.send(response).unwrap();
+        let turns: Vec<_> = self
+            .games
+            .values()
+            .filter_map(|game| {
+                let player_id = game.current_turn()?;
+                let players = game.get_players();
+                let players: Vec<_> = players.into_iter().filter(|&p| p != player_id).collect();
+                Some((player_id, players))
+            })
+            .collect();
+        for (player_id, players) in turns {
+            if let Some(waiters) = self.waiters.remove(&player_id) {
+                let last_attack_result = self.last_attack_results.remove(&player_id.game_id());
+                for (connection_id, id) in waiters {
+                    let response =
+                        Response::WaitForTurn(last_attack_result.clone(), players.clone());
+                    self.send(connection_id, Some(id), response);
                 }
             }
         }
     }
 
+    /// Queue an event for every player in the game but `except`.
+    fn publish(&mut self, game_id: GameId, except: Option<PlayerId>, event: GameEvent) {
+        self.events.push((game_id, except, event));
+    }
+
+    /// Send queued events to the subscribers of the players they are meant for.
+    fn flush_events(&mut self) {
+        for (game_id, except, event) in std::mem::take(&mut self.events) {
+            let players = match self.game(game_id) {
+                Ok(game) => game.get_players(),
+                Err(_) => continue,
+            };
+            for player_id in players.into_iter().filter(|&p| Some(p) != except) {
+                let subscribers = self.subscribers.get(&player_id).cloned();
+                for connection_id in subscribers.unwrap_or_default() {
+                    self.send(connection_id, None, Response::Event(event.clone()));
+                }
+            }
+        }
+    }
+
+    fn take_outbox(&mut self) -> Outbox {
+        self.flush_events();
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Subscribe the connection to the player's events, and describe the game so far.
+    fn subscribe(
+        &mut self,
+        connection_id: ConnectionId,
+        player_id: PlayerId,
+    ) -> Result<Vec<GameEvent>> {
+        let game = self.game(player_id.game_id())?;
+        let mut events = vec![];
+        for other in game.get_players().into_iter().filter(|&p| p != player_id) {
+            let opponent = game.get_player(other)?;
+            events.push(GameEvent::OpponentJoined(other, opponent.name().into()));
+            if opponent.ships_placed() {
+                events.push(GameEvent::OpponentReady(other));
+            }
+        }
+        if let Some(series) = series_score(game) {
+            events.push(GameEvent::Series(series));
+        }
+        if let Some((player, chosen_by)) = game.first_turn() {
+            events.push(GameEvent::FirstTurn { player, chosen_by });
+        }
+        // There is no winner to speak of until the game has started.
+        match (game.current_turn(), game.winner()) {
+            (Some(_), Some(winner)) => events.push(GameEvent::GameOver { winner }),
+            (Some(turn), None) => events.push(GameEvent::Turn(turn)),
+            (None, _) => {}
+        }
+
+        let subscribers = self.subscribers.entry(player_id).or_default();
+        if !subscribers.contains(&connection_id) {
+            subscribers.push(connection_id);
+        }
+        Ok(events)
+    }
+
+    fn send(&mut self, connection_id: ConnectionId, id: Option<RequestId>, response: Response) {
+        info!("{:#?}", &response);
+        let message = ServerMessage { id, response };
+        self.outbox.push((connection_id, Outbound::Send(message)));
+    }
+
+    /// Let the connection act on behalf of the given player from now on.
+    fn bind(&mut self, connection_id: ConnectionId, player_id: PlayerId) {
+        if let Some(connection) = self.connections.get_mut(&connection_id) {
+            connection.players.insert(player_id);
+            self.disconnected.remove(&player_id);
+        }
+    }
+
+    /// Whether the connection acts on behalf of any of the game's players.
+    fn is_playing(&self, connection_id: ConnectionId, game_id: GameId) -> bool {
+        self.connections
+            .get(&connection_id)
+            .into_iter()
+            .flat_map(|connection| &connection.players)
+            .any(|player_id| player_id.game_id() == game_id)
+    }
+
+    fn is_connected(&self, player_id: PlayerId) -> bool {
+        self.connections
+            .values()
+            .any(|c| c.players.contains(&player_id))
+    }
+
+    /// The given player has no connections left, so tell any opponents waiting for their turn.
+    fn player_disconnected(&mut self, player_id: PlayerId) {
+        let players = match self.game(player_id.game_id()) {
+            Ok(game) => game.get_players(),
+            Err(_) => return,
+        };
+        self.disconnected.insert(player_id, self.now);
+        info!("player {} disconnected", player_id);
+
+        let event = GameEvent::OpponentDisconnected(player_id);
+        self.publish(player_id.game_id(), Some(player_id), event);
+
+        for other in players.into_iter().filter(|&p| p != player_id) {
+            for (connection_id, id) in self.waiters.remove(&other).unwrap_or_default() {
+                let response = Response::OpponentDisconnected(player_id);
+                self.send(connection_id, Some(id), response);
+            }
+        }
+    }
+
+    /// When the given player lost their last connection, or `None` if they are still connected.
+    pub fn disconnected_since(&self, player_id: PlayerId) -> Option<Instant> {
+        self.disconnected.get(&player_id).cloned()
+    }
+
     fn winner(&mut self, game_id: GameId) -> Result<Option<PlayerId>> {
         Ok(self.game(game_id)?.winner())
     }
 
-    pub fn handle_request(&mut self, request: Request) -> Receiver<Response> {
+    /// Register a new connection. Its first request must be a `Hello`. Fails if there are already
+    /// `Limits::max_connections`, in which case the host should pass the error on and hang up.
+    pub fn connect(&mut self) -> Result<ConnectionId> {
+        if self.connections.len() >= self.limits.max_connections {
+            return Err(Error::ServerFull);
+        }
+        let connection_id = ConnectionId(self.next_connection_id);
+        self.next_connection_id += 1;
+        let connection = Connection {
+            connected: self.now,
+            codec: None,
+            players: HashSet::new(),
+            games: HashSet::new(),
+            recent_requests: VecDeque::new(),
+        };
+        self.connections.insert(connection_id, connection);
+        Ok(connection_id)
+    }
+
+    /// The connection went away.
+    pub fn disconnect(&mut self, connection_id: ConnectionId) -> Outbox {
+        if let Some(connection) = self.connections.remove(&connection_id) {
+            for player_id in connection.players {
+                if let Some(subscribers) = self.subscribers.get_mut(&player_id) {
+                    subscribers.retain(|&c| c != connection_id);
+                }
+                if let Some(waiters) = self.waiters.get_mut(&player_id) {
+                    waiters.retain(|&(c, _)| c != connection_id);
+                }
+                if !self.is_connected(player_id) {
+                    self.player_disconnected(player_id);
+                }
+            }
+        }
+        self.take_outbox()
+    }
+
+    /// Advance the server's clock. Hosts should call this every `TICK_INTERVAL`.
+    pub fn tick(&mut self, now: Instant) -> Outbox {
+        self.now = now;
+        self.reap();
+        self.close_silent_connections();
+        if now.saturating_duration_since(self.last_snapshot) >= SNAPSHOT_INTERVAL {
+            self.snapshot();
+            self.last_snapshot = now;
+        }
+        self.save_games();
+        self.take_outbox()
+    }
+
+    pub fn handle(&mut self, connection_id: ConnectionId, message: ClientMessage) -> Outbox {
+        let ClientMessage { id, request } = message;
         info!("{:#?}", &request);
-        let (sender, receiver) = channel();
+        let greeted = self
+            .connections
+            .get(&connection_id)
+            .map(|c| c.codec.is_some());
+        match greeted {
+            Some(false) => self.greet(connection_id, id, &request),
+            Some(true) if !self.allow_request(connection_id) => {
+                self.send(connection_id, Some(id), Response::Error(Error::RateLimited));
+            }
+            Some(true) => {
+                if let Some(response) = self.handle_request(connection_id, id, request) {
+                    self.send(connection_id, Some(id), response);
+                }
+                self.check_waiters();
+            }
+            None => {}
+        }
+        self.take_outbox()
+    }
+
+    /// Whether the connection may make another request, counting it if so.
+    fn allow_request(&mut self, connection_id: ConnectionId) -> bool {
+        let (now, max) = (self.now, self.limits.max_requests_per_second);
+        match self.connections.get_mut(&connection_id) {
+            Some(connection) => {
+                within_rate(&mut connection.recent_requests, now, REQUEST_WINDOW, max)
+            }
+            None => false,
+        }
+    }
+
+    /// Close the connections which haven't said `Hello` in time, so they don't hold on to a slot.
+    fn close_silent_connections(&mut self) {
+        let now = self.now;
+        let silent: Vec<_> = self
+            .connections
+            .iter()
+            .filter(|(_, c)| {
+                c.codec.is_none() && now.saturating_duration_since(c.connected) >= HANDSHAKE_TIMEOUT
+            })
+            .map(|(&connection_id, _)| connection_id)
+            .collect();
+        for connection_id in silent {
+            info!("abandoning connection which never said hello");
+            self.connections.remove(&connection_id);
+            self.outbox.push((connection_id, Outbound::Close));
+        }
+    }
+
+    fn greet(&mut self, connection_id: ConnectionId, id: RequestId, request: &Request) {
+        let response = handshake(request);
+        let accepted = matches!(response, Response::Hello { .. });
+        self.send(connection_id, Some(id), response);
+        match (accepted, request, self.connections.get_mut(&connection_id)) {
+            (true, Request::Hello { capabilities, .. }, Some(connection)) => {
+                let codec = Codec::negotiate(capabilities);
+                connection.codec = Some(codec);
+                self.outbox.push((connection_id, Outbound::SetCodec(codec)));
+            }
+            _ => {
+                info!("abandoning connection due to failed handshake");
+                self.connections.remove(&connection_id);
+                self.outbox.push((connection_id, Outbound::Close));
+            }
+        }
+    }
+
+    /// Returns `None` if the response will be sent later.
+    fn handle_request(
+        &mut self,
+        connection_id: ConnectionId,
+        id: RequestId,
+        request: Request,
+    ) -> Option<Response> {
         let response = match request {
+            Request::Hello { .. } => handshake(&request),
             Request::AddPlayer(game_id, name) => self
                 .add_player(game_id, &name)
-                .map(Response::AddPlayer)
+                .map(|(player_id, token)| {
+                    self.bind(connection_id, player_id);
+                    Response::AddPlayer(player_id, token)
+                })
                 .into(),
-            Request::PlaceShip(player_id, ship_id, location, direction) => self
-                .place_ship(player_id, ship_id, location, direction)
+            Request::PlaceShip(token, ship_id, location, direction) => self
+                .authorize(connection_id, &token)
+                .and_then(|player_id| self.place_ship(player_id, ship_id, location, direction))
                 .map(|()| Response::PlaceShip(ship_id, location, direction))
                 .into(),
-            Request::Advance(player_a_id, player_b_id, location) => self
-                .advance(player_a_id, player_b_id, location)
+            Request::Advance(token, player_b_id, location) => self
+                .authorize(connection_id, &token)
+                .and_then(|player_a_id| self.advance(player_a_id, player_b_id, location))
                 .map(|r| Response::Advance(location, r))
                 .into(),
-            Request::WaitForTurn(player_id) => {
-                let response = self.wait_for_turn(player_id);
-                if let Ok(None) = &response {
-                    self.waiters.insert(player_id, sender);
-                    return receiver;
-                } else {
-                    response.map(|c| c.unwrap()).into()
+            Request::WaitForTurn(token) => {
+                match self
+                    .authorize(connection_id, &token)
+                    .and_then(|player_id| Ok((player_id, self.wait_for_turn(player_id)?)))
+                {
+                    Ok((player_id, None)) => {
+                        let waiters = self.waiters.entry(player_id).or_default();
+                        waiters.push((connection_id, id));
+                        return None;
+                    }
+                    Ok((_, Some(response))) => response,
+                    Err(e) => Response::Error(e),
                 }
             }
             Request::Winner(game_id) => self.winner(game_id).map(Response::Winner).into(),
-            Request::CreateGame => Response::CreateGame(self.create_game()),
-            Request::JoinGame(player_id) => self
-                .join_game(player_id)
-                .map(|p| Response::JoinedGame(player_id, p))
+            Request::CreateGame => self
+                .create_game_for(connection_id, &GameOptions::default())
+                .map(created)
+                .into(),
+            Request::CreatePrivateGame => {
+                let options = GameOptions {
+                    private: true,
+                    ..GameOptions::default()
+                };
+                self.create_game_for(connection_id, &options)
+                    .map(created)
+                    .into()
+            }
+            Request::CreateCustomGame(options) => self
+                .create_game_for(connection_id, &options)
+                .map(created)
+                .into(),
+            Request::JoinWithCode(join_code, name) => self
+                .join_with_code(&join_code, &name)
+                .map(|(player_id, token)| {
+                    self.bind(connection_id, player_id);
+                    Response::AddPlayer(player_id, token)
+                })
+                .into(),
+            Request::Chat(token, message) => self
+                .authorize(connection_id, &token)
+                .and_then(|player_id| self.chat(player_id, message))
+                .map(|()| Response::ChatSent)
+                .into(),
+            Request::Rematch(token) => self
+                .authorize(connection_id, &token)
+                .and_then(|player_id| self.rematch(player_id))
+                .map(|()| Response::Rematch)
+                .into(),
+            Request::ListOpenGames => {
+                let mut open_games = self.open_games();
+                open_games.truncate(MAX_OPEN_GAMES_LISTED);
+                Response::OpenGames(open_games)
+            }
+            Request::QuickMatch(name) => self
+                .quick_match(connection_id, &name)
+                .map(|(player_id, token)| {
+                    self.bind(connection_id, player_id);
+                    Response::QuickMatch(player_id, token)
+                })
+                .into(),
+            Request::Subscribe(token) => self
+                .authorize(connection_id, &token)
+                .and_then(|player_id| self.subscribe(connection_id, player_id))
+                .map(Response::Subscribed)
+                .into(),
+            Request::GetGameState(player_id) => self
+                .require_bound(connection_id, player_id)
+                .and_then(|()| self.game_state(player_id))
+                .map(|state| Response::GameState(Box::new(state)))
+                .into(),
+            Request::JoinGame(token) => self
+                .authenticate(&token)
+                .and_then(|player_id| Ok((player_id, self.join_game(player_id)?)))
+                .map(|(player_id, player)| {
+                    self.bind(connection_id, player_id);
+                    Response::JoinedGame(player_id, player)
+                })
                 .into(),
         };
-        info!("{:#?}", &response);
-        sender.send(response).unwrap();
+        Some(response)
+    }
+}
+
+impl Drop for GameServer {
+    /// Save what changed since the last tick, so a server shutting down doesn't lose it.
+    fn drop(&mut self) {
+        self.save_games();
+    }
+}
+
+/// How the game's series is going, unless it is a single game.
+fn series_score(game: &Game) -> Option<SeriesScore> {
+    if game.best_of() > 1 {
+        Some(SeriesScore {
+            best_of: game.best_of(),
+            wins: game.series_score(),
+        })
+    } else {
+        None
+    }
+}
+
+/// Note another use of something allowed `burst` times per `window`, given when it was last used,
+/// oldest first. Returns false without noting it if that would be too many.
+fn within_rate(
+    recent: &mut VecDeque<Instant>,
+    now: Instant,
+    window: Duration,
+    burst: usize,
+) -> bool {
+    while matches!(recent.front(), Some(&used) if now.saturating_duration_since(used) >= window) {
+        recent.pop_front();
+    }
+    if recent.len() >= burst {
+        return false;
+    }
+    recent.push_back(now);
+    true
+}
+
+fn created((game_id, join_code): (GameId, Option<JoinCode>)) -> Response {
+    match join_code {
+        Some(join_code) => Response::CreatePrivateGame(game_id, join_code),
+        None => Response::CreateGame(game_id),
+    }
+}
+
+#[cfg(test)]
+fn request(server: &mut GameServer, connection_id: ConnectionId, request: Request) -> Outbox {
+    let message = ClientMessage {
+        id: RequestId(0),
+        request,
+    };
+    server.handle(connection_id, message)
+}
+
+#[cfg(test)]
+fn only_response(outbox: Outbox) -> (ConnectionId, Response) {
+    match &outbox[..] {
+        [(connection_id, Outbound::Send(message))] => (*connection_id, message.response.clone()),
+        _ => panic!("unexpected outbox {:?}", outbox),
+    }
+}
+
+#[cfg(test)]
+fn greeted_connection(server: &mut GameServer) -> ConnectionId {
+    let connection_id = server.connect().unwrap();
+    let hello = Request::Hello {
+        version: protocol::PROTOCOL_VERSION,
+        capabilities: vec![],
+    };
+    request(server, connection_id, hello);
+    connection_id
+}
+
+#[test]
+fn test_player_requests_require_token() {
+    let mut server = GameServer::new();
+    let connection = greeted_connection(&mut server);
+    let game_id = match only_response(request(&mut server, connection, Request::CreateGame)) {
+        (_, Response::CreateGame(game_id)) => game_id,
+        r => panic!("unexpected response {:?}", r),
+    };
+    let add_player = Request::AddPlayer(game_id, "a".into());
+    let (player_id, token) = match only_response(request(&mut server, connection, add_player)) {
+        (_, Response::AddPlayer(player_id, token)) => (player_id, token),
+        r => panic!("unexpected response {:?}", r),
+    };
+
+    let forged: PlayerToken = player_id.to_string().parse().unwrap();
+    assert!(matches!(
+        only_response(request(&mut server, connection, Request::JoinGame(forged))),
+        (_, Response::Error(Error::InvalidPlayerToken))
+    ));
+
+    let other = greeted_connection(&mut server);
+    let wait = Request::WaitForTurn(token.clone());
+    assert!(matches!(
+        only_response(request(&mut server, other, wait)),
+        (_, Response::Error(Error::PlayerNotOnConnection(id))) if id == player_id
+    ));
+    assert!(matches!(
+        only_response(request(&mut server, other, Request::JoinGame(token))),
+        (_, Response::JoinedGame(id, _)) if id == player_id
+    ));
+}
+
+#[test]
+fn test_waiting_opponent_told_about_disconnect() {
+    let mut server = GameServer::new();
+    let game_id = server.create_game();
+    let (connection_a, connection_b) = (
+        greeted_connection(&mut server),
+        greeted_connection(&mut server),
+    );
+    let (token_a, _) = match only_response(request(
+        &mut server,
+        connection_a,
+        Request::AddPlayer(game_id, "a".into()),
+    )) {
+        (_, Response::AddPlayer(player_id, token)) => (token, player_id),
+        r => panic!("unexpected response {:?}", r),
+    };
+    let (token_b, player_b) = match only_response(request(
+        &mut server,
+        connection_b,
+        Request::AddPlayer(game_id, "b".into()),
+    )) {
+        (_, Response::AddPlayer(player_id, token)) => (token, player_id),
+        r => panic!("unexpected response {:?}", r),
+    };
+    let rejoined = greeted_connection(&mut server);
+    request(&mut server, rejoined, Request::JoinGame(token_b));
+
+    assert!(request(&mut server, connection_a, Request::WaitForTurn(token_a)).is_empty());
+
+    assert!(server.disconnect(connection_b).is_empty());
+    assert_eq!(server.disconnected_since(player_b), None);
+
+    assert!(matches!(
+        only_response(server.disconnect(rejoined)),
+        (id, Response::OpponentDisconnected(player_id))
+            if id == connection_a && player_id == player_b
+    ));
+    assert!(server.disconnected_since(player_b).is_some());
+}
+
+#[test]
+fn test_handshake() {
+    let hello = |version| Request::Hello {
+        version,
+        capabilities: vec![protocol::Capability::Bincode],
+    };
+    assert!(matches!(
+        handshake(&hello(protocol::PROTOCOL_VERSION)),
+        Response::Hello { version, .. } if version == protocol::PROTOCOL_VERSION
+    ));
+    assert!(matches!(
+        handshake(&hello(protocol::PROTOCOL_VERSION + 1)),
+        Response::Hello { version, .. } if version == protocol::PROTOCOL_VERSION
+    ));
+    assert!(matches!(
+        handshake(&hello(protocol::MIN_PROTOCOL_VERSION - 1)),
+        Response::Error(Error::UnsupportedProtocolVersion(_))
+    ));
+    assert!(matches!(
+        handshake(&Request::CreateGame),
+        Response::Error(Error::HandshakeRequired)
+    ));
+
+    let mut server = GameServer::new();
+    let connection = server.connect().unwrap();
+    assert!(matches!(
+        &request(&mut server, connection, hello(protocol::PROTOCOL_VERSION))[..],
+        [
+            (_, Outbound::Send(_)),
+            (_, Outbound::SetCodec(Codec::Bincode))
+        ]
+    ));
+
+    let connection = server.connect().unwrap();
+    assert!(matches!(
+        &request(&mut server, connection, Request::CreateGame)[..],
+        [(_, Outbound::Send(_)), (_, Outbound::Close)]
+    ));
+    assert!(request(&mut server, connection, Request::CreateGame).is_empty());
+}
+
+#[test]
+fn test_events_pushed_to_subscribers() {
+    let mut server = GameServer::new();
+    let game_id = server.create_game();
+    let first_turn = super::FirstTurn::Joiner;
+    server.game(game_id).unwrap().set_first_turn(first_turn);
+    let (connection_a, connection_b) = (
+        greeted_connection(&mut server),
+        greeted_connection(&mut server),
+    );
+    let mut join = |connection_id, name: &str| match only_response(request(
+        &mut server,
+        connection_id,
+        Request::AddPlayer(game_id, name.into()),
+    )) {
+        (_, Response::AddPlayer(player_id, token)) => (player_id, token),
+        r => panic!("unexpected response {:?}", r),
+    };
+    let (player_a, token_a) = join(connection_a, "a");
+    let (player_b, token_b) = join(connection_b, "b");
+    for &player_id in &[player_a, player_b] {
+        let game = server.game(game_id).unwrap();
+        game.get_player_mut(player_id)
+            .unwrap()
+            .place_ships_automatically();
+    }
+
+    let subscribe = Request::Subscribe(token_a);
+    assert!(matches!(
+        only_response(request(&mut server, connection_a, subscribe)),
+        (_, Response::Subscribed(events)) if events == [
+            GameEvent::OpponentJoined(player_b, "b".into()),
+            GameEvent::OpponentReady(player_b),
+            GameEvent::FirstTurn { player: player_b, chosen_by: first_turn },
+            GameEvent::Turn(player_b),
+        ]
+    ));
+    request(
+        &mut server,
+        connection_b,
+        Request::Subscribe(token_b.clone()),
+    );
+
+    let location = Location::new(0, 0);
+    let outbox = request(
+        &mut server,
+        connection_b,
+        Request::Advance(token_b, player_a, location),
+    );
+    let events: Vec<_> = outbox
+        .iter()
+        .filter_map(|(connection_id, outbound)| match outbound {
+            Outbound::Send(ServerMessage {
+                id: None,
+                response: Response::Event(event),
+            }) => Some((*connection_id, event.clone())),
+            _ => None,
+        })
+        .collect();
+    assert!(matches!(
+        &outbox[0],
+        (id, Outbound::Send(ServerMessage { response: Response::Advance(..), .. }))
+            if *id == connection_b
+    ));
+    for &connection_id in &[connection_a, connection_b] {
+        let received: Vec<_> = events
+            .iter()
+            .filter(|(id, _)| *id == connection_id)
+            .map(|(_, e)| e.clone())
+            .collect();
+        assert!(matches!(
+            &received[..],
+            [
+                GameEvent::ShotFired { attacker, target, .. },
+                GameEvent::Turn(turn),
+            ] if *attacker == player_b && *target == player_a && *turn == player_a
+        ));
+    }
+}
+
+#[test]
+fn test_every_waiting_session_answered() {
+    let mut server = GameServer::new();
+    let game_id = server.create_game();
+    server
+        .game(game_id)
+        .unwrap()
+        .set_first_turn(super::FirstTurn::Joiner);
+    let (player_a, token_a) = server.add_player(game_id, "a").unwrap();
+    let (player_b, token_b) = server.add_player(game_id, "b").unwrap();
+    for &player_id in &[player_a, player_b] {
+        let game = server.game(game_id).unwrap();
+        game.get_player_mut(player_id)
+            .unwrap()
+            .place_ships_automatically();
+    }
+
+    let sessions: Vec<_> = (0..3).map(|_| greeted_connection(&mut server)).collect();
+    for &connection_id in &sessions {
+        request(
+            &mut server,
+            connection_id,
+            Request::JoinGame(token_a.clone()),
+        );
+        let wait = Request::WaitForTurn(token_a.clone());
+        assert!(request(&mut server, connection_id, wait).is_empty());
+    }
+    server.disconnect(sessions[1]);
+
+    let connection_b = greeted_connection(&mut server);
+    request(
+        &mut server,
+        connection_b,
+        Request::JoinGame(token_b.clone()),
+    );
+    let advance = Request::Advance(token_b, player_a, Location::new(0, 0));
+    let answered: Vec<_> = request(&mut server, connection_b, advance)
+        .into_iter()
+        .filter_map(|(connection_id, outbound)| match outbound {
+            Outbound::Send(ServerMessage {
+                response: Response::WaitForTurn(Some(_), players),
+                ..
+            }) if players == [player_b] => Some(connection_id),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(answered, [sessions[0], sessions[2]]);
+    assert!(!server.waiters.contains_key(&player_a));
+}
+
+#[test]
+fn test_last_attack_result_kept_per_game() {
+    let mut server = GameServer::new();
+    let mut players = vec![];
+    for &first_turn in &[super::FirstTurn::Creator, super::FirstTurn::Joiner] {
+        let game_id = server.create_game();
+        server.game(game_id).unwrap().set_first_turn(first_turn);
+        let a = server.add_player(game_id, "a").unwrap();
+        let b = server.add_player(game_id, "b").unwrap();
+        for player_id in [a.0, b.0] {
+            let game = server.game(game_id).unwrap();
+            game.get_player_mut(player_id)
+                .unwrap()
+                .place_ships_automatically();
+        }
+        players.push((a, b));
+    }
+    let ((_, token_a1), (player_b1, _)) = players[0].clone();
+    let ((player_a2, token_a2), (player_b2, token_b2)) = players[1].clone();
+    let connection = greeted_connection(&mut server);
+    for token in [&token_a1, &token_a2, &token_b2] {
+        request(&mut server, connection, Request::JoinGame(token.clone()));
+    }
+
+    // Nobody in the second game is waiting when its shot is fired.
+    let advance = Request::Advance(token_b2, player_a2, Location::new(0, 0));
+    request(&mut server, connection, advance);
 
-        self.check_waiters();
+    // It's the first game's first turn, so there is no shot to tell its player about.
+    let wait = Request::WaitForTurn(token_a1);
+    match only_response(request(&mut server, connection, wait)) {
+        (_, Response::WaitForTurn(None, players)) => assert_eq!(players, [player_b1]),
+        r => panic!("unexpected response {:?}", r),
+    }
+    let wait = Request::WaitForTurn(token_a2);
+    match only_response(request(&mut server, connection, wait)) {
+        (_, Response::WaitForTurn(Some((location, _)), players)) => {
+            assert_eq!(location, Location::new(0, 0));
+            assert_eq!(players, [player_b2]);
+        }
+        r => panic!("unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn test_games_survive_restart() {
+    let directory = tempfile::tempdir().unwrap();
+    let storage = storage::FileStorage::open(directory.path()).unwrap();
+    let mut server = GameServer::with_storage(Box::new(storage)).unwrap();
+
+    let connection = greeted_connection(&mut server);
+    let game_id = match only_response(request(&mut server, connection, Request::CreateGame)) {
+        (_, Response::CreateGame(game_id)) => game_id,
+        r => panic!("unexpected response {:?}", r),
+    };
+    let mut tokens = vec![];
+    for name in &["a", "b"] {
+        let add_player = Request::AddPlayer(game_id, name.to_string());
+        match only_response(request(&mut server, connection, add_player)) {
+            (_, Response::AddPlayer(player_id, token)) => tokens.push((player_id, token)),
+            r => panic!("unexpected response {:?}", r),
+        }
+    }
+    let game = server.game(game_id).unwrap();
+    for (player_id, _) in &tokens {
+        game.get_player_mut(*player_id)
+            .unwrap()
+            .place_ships_automatically();
+    }
+    server.snapshot();
+    let attacker = server.game(game_id).unwrap().current_turn().unwrap();
+    let (attacker, target) = if attacker == tokens[0].0 {
+        (&tokens[0], &tokens[1])
+    } else {
+        (&tokens[1], &tokens[0])
+    };
+    let advance = Request::Advance(attacker.1.clone(), target.0, Location::new(0, 0));
+    request(&mut server, connection, advance);
+    drop(server);
+
+    let storage = storage::FileStorage::open(directory.path()).unwrap();
+    let mut server = GameServer::with_storage(Box::new(storage)).unwrap();
+    for (player_id, token) in &tokens {
+        assert_eq!(server.authenticate(token).unwrap(), *player_id);
+    }
+    let game = server.game(game_id).unwrap();
+    assert_eq!(game.current_turn(), Some(target.0));
+    let field = game.get_player(attacker.0).unwrap().speculative_field();
+    assert_ne!(field.get(Location::new(0, 0)).unwrap(), crate::Cell::Empty);
+}
+
+#[test]
+fn test_removed_game_ids_not_reused_after_restart() {
+    let directory = tempfile::tempdir().unwrap();
+    let storage = storage::FileStorage::open(directory.path()).unwrap();
+    let mut server = GameServer::with_storage(Box::new(storage)).unwrap();
+    server.create_game();
+    let removed = server.create_game();
+    server.tick(server.now);
+    server.remove_game(removed, GameRemoval::Abandoned);
+    server.snapshot();
+    drop(server);
+
+    let storage = storage::FileStorage::open(directory.path()).unwrap();
+    let mut server = GameServer::with_storage(Box::new(storage)).unwrap();
+    assert!(server.create_game() > removed);
+}
+
+#[test]
+fn test_old_games_removed() {
+    let mut server = GameServer::new();
+    let start = server.now;
+    let connection = greeted_connection(&mut server);
+    let abandoned = server.create_game();
+    let idle = server.create_game();
+    let mut tokens = vec![];
+    for name in &["a", "b"] {
+        let add_player = Request::AddPlayer(idle, name.to_string());
+        match only_response(request(&mut server, connection, add_player)) {
+            (_, Response::AddPlayer(_, token)) => tokens.push(token),
+            r => panic!("unexpected response {:?}", r),
+        }
+    }
+    request(
+        &mut server,
+        connection,
+        Request::Subscribe(tokens[0].clone()),
+    );
+
+    let minutes = |m: u64| start + Duration::from_secs(m * 60);
+    assert!(server.tick(minutes(11)).is_empty());
+    assert_eq!(server.removed_games().abandoned, 1);
+    assert!(matches!(
+        only_response(request(&mut server, connection, Request::Winner(abandoned))),
+        (_, Response::Error(Error::GameRemoved(id, GameRemoval::Abandoned))) if id == abandoned
+    ));
+
+    // Subscribers hear about it straight away, and anyone else once they ask.
+    assert!(matches!(
+        only_response(server.tick(minutes(25 * 60))),
+        (c, Response::Error(Error::GameRemoved(id, GameRemoval::Idle)))
+            if c == connection && id == idle
+    ));
+    assert!(matches!(
+        only_response(request(&mut server, connection, Request::Subscribe(tokens[1].clone()))),
+        (_, Response::Error(Error::GameRemoved(id, GameRemoval::Idle))) if id == idle
+    ));
+
+    server.tick(minutes(50 * 60));
+    assert!(matches!(
+        only_response(request(&mut server, connection, Request::Winner(abandoned))),
+        (_, Response::Error(Error::UnknownGame(_)))
+    ));
+    assert!(matches!(
+        only_response(request(
+            &mut server,
+            connection,
+            Request::JoinGame(tokens[0].clone())
+        )),
+        (_, Response::Error(Error::InvalidPlayerToken))
+    ));
+    assert!(server.create_game() > idle);
+    assert_eq!(
+        server.removed_games(),
+        RemovedGames {
+            abandoned: 1,
+            finished: 0,
+            idle: 1
+        }
+    );
+}
+
+#[test]
+fn test_quick_match() {
+    let mut server = GameServer::new();
+    let connection = greeted_connection(&mut server);
+    let list = |server: &mut GameServer| match only_response(request(
+        server,
+        connection,
+        Request::ListOpenGames,
+    )) {
+        (_, Response::OpenGames(open_games)) => open_games,
+        r => panic!("unexpected response {:?}", r),
+    };
+    let quick_match = |server: &mut GameServer, connection: ConnectionId, name: &str| {
+        let outbox = request(server, connection, Request::QuickMatch(name.into()));
+        match only_response(outbox) {
+            (_, Response::QuickMatch(player_id, _)) => player_id,
+            r => panic!("unexpected response {:?}", r),
+        }
+    };
+
+    // An empty game isn't waiting on anyone.
+    server.create_game();
+    assert_eq!(list(&mut server), []);
+
+    let host = quick_match(&mut server, connection, "a");
+    let open_games = list(&mut server);
+    assert_eq!(open_games.len(), 1);
+    assert_eq!(open_games[0].game_id, host.game_id());
+    assert_eq!(open_games[0].host, "a");
+
+    // Matching again from the same connection waits in a game of its own, instead of joining
+    // the one it is hosting.
+    let second = quick_match(&mut server, connection, "a");
+    assert_ne!(second.game_id(), host.game_id());
+    assert_eq!(list(&mut server).len(), 2);
+
+    let other_connection = greeted_connection(&mut server);
+    let opponent = quick_match(&mut server, other_connection, "b");
+    assert_eq!(opponent.game_id(), host.game_id());
+    let open_games = list(&mut server);
+    assert_eq!(open_games.len(), 1);
+    assert_eq!(open_games[0].game_id, second.game_id());
+}
+
+#[test]
+fn test_open_games_fit_in_a_frame() {
+    let mut server = GameServer::new();
+    let connection = greeted_connection(&mut server);
+    // Names which take as much room as they can once encoded.
+    let name = "\u{1}".repeat(server.limits.max_name_length);
+    let mut game_ids = vec![];
+    for _ in 0..1000 {
+        let game_id = server.create_game();
+        server.add_player(game_id, &name).unwrap();
+        game_ids.push(game_id);
+    }
+
+    let outbox = request(&mut server, connection, Request::ListOpenGames);
+    let message = match &outbox[..] {
+        [(_, Outbound::Send(message))] => message,
+        _ => panic!("unexpected outbox {:?}", outbox),
+    };
+    for codec in &[Codec::Json, Codec::Bincode] {
+        assert!(codec.encode(message).unwrap().len() <= DEFAULT_MAX_FRAME_SIZE);
+    }
+    match &message.response {
+        Response::OpenGames(open_games) => {
+            let listed: Vec<_> = open_games.iter().map(|g| g.game_id).collect();
+            assert_eq!(listed, game_ids[..MAX_OPEN_GAMES_LISTED]);
+        }
+        r => panic!("unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn test_private_games_need_join_code() {
+    let mut server = GameServer::new();
+    let connection = greeted_connection(&mut server);
+    let (game_id, join_code) =
+        match only_response(request(&mut server, connection, Request::CreatePrivateGame)) {
+            (_, Response::CreatePrivateGame(game_id, join_code)) => (game_id, join_code),
+            r => panic!("unexpected response {:?}", r),
+        };
+    let join = Request::JoinWithCode(join_code.clone(), "a".into());
+    assert!(matches!(
+        only_response(request(&mut server, connection, join)),
+        (_, Response::AddPlayer(player_id, _)) if player_id.game_id() == game_id
+    ));
+
+    // Not to be found, or joined, without the code.
+    assert!(matches!(
+        only_response(request(&mut server, connection, Request::ListOpenGames)),
+        (_, Response::OpenGames(open_games)) if open_games.is_empty()
+    ));
+    let add_player = Request::AddPlayer(game_id, "b".into());
+    assert!(matches!(
+        only_response(request(&mut server, connection, add_player)),
+        (_, Response::Error(Error::JoinCodeRequired(id))) if id == game_id
+    ));
+    let wrong_code = (0..)
+        .map(|_| JoinCode::random())
+        .find(|c| *c != join_code)
+        .unwrap();
+    let join = Request::JoinWithCode(wrong_code, "b".into());
+    assert!(matches!(
+        only_response(request(&mut server, connection, join)),
+        (_, Response::Error(Error::UnknownJoinCode))
+    ));
+    assert!(matches!(
+        only_response(request(&mut server, connection, Request::QuickMatch("b".into()))),
+        (_, Response::QuickMatch(player_id, _)) if player_id.game_id() != game_id
+    ));
+}
+
+#[test]
+fn test_chat_limits() {
+    let mut server = GameServer::new();
+    let game_id = server.create_game();
+    let mut players = vec![];
+    for name in &["a", "b"] {
+        let connection = greeted_connection(&mut server);
+        let add_player = Request::AddPlayer(game_id, name.to_string());
+        let token = match only_response(request(&mut server, connection, add_player)) {
+            (_, Response::AddPlayer(_, token)) => token,
+            r => panic!("unexpected response {:?}", r),
+        };
+        players.push((connection, token));
+    }
+    for (connection, token) in &players {
+        request(&mut server, *connection, Request::Subscribe(token.clone()));
+    }
+    let (connection_a, token_a) = players[0].clone();
+    let connection_b = players[1].0;
+    let chat = |server: &mut GameServer, message: ChatMessage| {
+        request(
+            server,
+            connection_a,
+            Request::Chat(token_a.clone(), message),
+        )
+    };
+
+    let outbox = chat(&mut server, ChatMessage::Text("hi".into()));
+    assert!(matches!(
+        &outbox[..],
+        [
+            (c1, Outbound::Send(ServerMessage { response: Response::ChatSent, .. })),
+            (c2, Outbound::Send(ServerMessage { response: Response::Event(GameEvent::Chat { name, .. }), .. })),
+        ] if *c1 == connection_a && *c2 == connection_b && name == "a"
+    ));
+
+    let long = ChatMessage::Text("x".repeat(protocol::MAX_CHAT_LENGTH + 1));
+    assert!(matches!(
+        only_response(chat(&mut server, long)),
+        (_, Response::Error(Error::ChatTooLong(_)))
+    ));
+    assert!(matches!(
+        only_response(chat(&mut server, ChatMessage::Text(" ".into()))),
+        (_, Response::Error(Error::EmptyChatMessage))
+    ));
+
+    let emote = ChatMessage::Emote(protocol::Emote::GoodLuck);
+    for _ in 1..CHAT_BURST {
+        assert_eq!(chat(&mut server, emote.clone()).len(), 2);
+    }
+    assert!(matches!(
+        only_response(chat(&mut server, emote.clone())),
+        (_, Response::Error(Error::ChatRateLimited))
+    ));
+    server.tick(server.now + CHAT_WINDOW);
+    assert_eq!(chat(&mut server, emote).len(), 2);
+}
+
+#[test]
+fn test_limits() {
+    let mut server = GameServer::new();
+    server.set_limits(Limits {
+        max_name_length: 4,
+        max_games_per_connection: 2,
+        max_requests_per_second: 4,
+        max_connections: 2,
+        ..Limits::default()
+    });
+    let connection = greeted_connection(&mut server);
+    let other = greeted_connection(&mut server);
+    assert!(matches!(server.connect(), Err(Error::ServerFull)));
+
+    let quick_match = Request::QuickMatch("toolong".into());
+    assert!(matches!(
+        only_response(request(&mut server, connection, quick_match)),
+        (_, Response::Error(Error::NameTooLong(4)))
+    ));
+    assert!(server.open_games().is_empty());
+
+    for _ in 0..2 {
+        assert!(matches!(
+            only_response(request(&mut server, connection, Request::CreateGame)),
+            (_, Response::CreateGame(_))
+        ));
+    }
+    assert!(matches!(
+        only_response(request(&mut server, connection, Request::CreateGame)),
+        (_, Response::Error(Error::TooManyGames(2)))
+    ));
+    assert!(matches!(
+        only_response(request(&mut server, other, Request::CreateGame)),
+        (_, Response::CreateGame(_))
+    ));
+
+    assert!(matches!(
+        only_response(request(&mut server, connection, Request::ListOpenGames)),
+        (_, Response::Error(Error::RateLimited))
+    ));
+    server.tick(server.now + REQUEST_WINDOW);
+    assert!(matches!(
+        only_response(request(&mut server, connection, Request::ListOpenGames)),
+        (_, Response::OpenGames(_))
+    ));
+
+    server.disconnect(other);
+    assert!(server.connect().is_ok());
+}
+
+#[test]
+fn test_rematch_series() {
+    let mut server = GameServer::new();
+    let a = greeted_connection(&mut server);
+    let b = greeted_connection(&mut server);
+    let options = GameOptions {
+        best_of: 3,
+        ..GameOptions::default()
+    };
+    let game_id = match only_response(request(&mut server, a, Request::CreateCustomGame(options))) {
+        (_, Response::CreateGame(game_id)) => game_id,
+        r => panic!("unexpected response {:?}", r),
+    };
+    let mut tokens = vec![];
+    for (connection, name) in &[(a, "a"), (b, "b")] {
+        let add_player = Request::AddPlayer(game_id, name.to_string());
+        match &request(&mut server, *connection, add_player)[0] {
+            (
+                _,
+                Outbound::Send(ServerMessage {
+                    response: Response::AddPlayer(_, token),
+                    ..
+                }),
+            ) => tokens.push(token.clone()),
+            r => panic!("unexpected outbox {:?}", r),
+        }
+    }
+    for (connection, token) in [a, b].iter().zip(&tokens) {
+        request(&mut server, *connection, Request::Subscribe(token.clone()));
+    }
+
+    // Have the first player sink all of the second player's ships.
+    let win = |server: &mut GameServer, game_id: GameId| {
+        let game = server.game(game_id).unwrap();
+        let mut players = game.get_players();
+        players.sort();
+        for &player_id in &players {
+            let mut ships: Vec<_> = game
+                .get_player(player_id)
+                .unwrap()
+                .ships()
+                .into_keys()
+                .collect();
+            ships.sort();
+            for (row, ship_id) in ships.into_iter().enumerate() {
+                let location = Location::new(0, row);
+                game.place_ship(player_id, ship_id, location, Direction::East)
+                    .unwrap();
+            }
+        }
+        while game.winner().is_none() {
+            game.current_turn = Some(players[0]);
+            game.advance_automatically(players[0], players[1]).unwrap();
+        }
+        players
+    };
+    let rematch = |server: &mut GameServer, connection, token: &PlayerToken| {
+        request(server, connection, Request::Rematch(token.clone()))
+    };
+
+    assert!(matches!(
+        only_response(rematch(&mut server, a, &tokens[0])),
+        (_, Response::Error(Error::GameNotOver))
+    ));
+    let old_players = win(&mut server, game_id);
+    assert!(matches!(
+        &rematch(&mut server, a, &tokens[0])[..],
+        [
+            (_, Outbound::Send(ServerMessage { response: Response::Rematch, .. })),
+            (c, Outbound::Send(ServerMessage { response: Response::Event(GameEvent::RematchOffered(_)), .. })),
+        ] if *c == b
+    ));
+    let outbox = rematch(&mut server, b, &tokens[1]);
+    let new_players = match &outbox[1] {
+        (
+            _,
+            Outbound::Send(ServerMessage {
+                response: Response::Event(GameEvent::Rematch { players, .. }),
+                ..
+            }),
+        ) => players.iter().map(|&(_, new)| new).collect::<Vec<_>>(),
+        r => panic!("unexpected outbox {:?}", r),
+    };
+    assert!(outbox
+        .iter()
+        .any(|(_, o)| matches!(o, Outbound::Send(ServerMessage {
+        response: Response::Event(GameEvent::Series(SeriesScore { best_of: 3, wins })),
+        ..
+    }) if wins[0].1 == 1)));
+
+    // The tokens now belong to the new game, where the loser shoots first.
+    let new_game_id = new_players[0].game_id();
+    assert!(new_game_id != game_id);
+    assert_eq!(server.authenticate(&tokens[0]), Ok(new_players[0]));
+    assert_eq!(
+        server.game(new_game_id).unwrap().current_turn,
+        Some(new_players[1])
+    );
+    assert!(old_players.iter().all(|p| !new_players.contains(p)));
+
+    // Winning the series starts a new one on the next rematch.
+    win(&mut server, new_game_id);
+    assert_eq!(
+        server.game(new_game_id).unwrap().series_winner(),
+        Some(new_players[0])
+    );
+    rematch(&mut server, a, &tokens[0]);
+    rematch(&mut server, b, &tokens[1]);
+    let player_id = server.authenticate(&tokens[0]).unwrap();
+    let game = server.game(player_id.game_id()).unwrap();
+    assert_eq!(game.best_of(), 3);
+    assert!(game.series_score().iter().all(|&(_, wins)| wins == 0));
+}
+
+#[test]
+fn test_game_state() {
+    let mut server = GameServer::new();
+    let game_id = server.create_game();
+    let mut players = vec![];
+    for name in &["a", "b"] {
+        let connection = greeted_connection(&mut server);
+        let add_player = Request::AddPlayer(game_id, name.to_string());
+        match only_response(request(&mut server, connection, add_player)) {
+            (_, Response::AddPlayer(player_id, token)) => {
+                players.push((connection, player_id, token))
+            }
+            r => panic!("unexpected response {:?}", r),
+        }
+    }
+    let (connection_a, player_a, token_a) = players[0].clone();
+    let (connection_b, player_b, _) = players[1].clone();
+
+    let game = server.game(game_id).unwrap();
+    for &player_id in &[player_a, player_b] {
+        let ships: Vec<_> = game
+            .get_player(player_id)
+            .unwrap()
+            .ships()
+            .into_keys()
+            .collect();
+        for (row, ship_id) in ships.into_iter().enumerate() {
+            game.place_ship(player_id, ship_id, Location::new(0, row), Direction::East)
+                .unwrap();
+        }
+    }
+    game.current_turn = Some(player_a);
+    let miss = Location::new(9, 9);
+    request(
+        &mut server,
+        connection_a,
+        Request::Advance(token_a, player_b, miss),
+    );
+
+    assert!(matches!(
+        only_response(request(
+            &mut server,
+            connection_a,
+            Request::GetGameState(player_b)
+        )),
+        (_, Response::Error(Error::PlayerNotOnConnection(_)))
+    ));
+    let state = match only_response(request(
+        &mut server,
+        connection_b,
+        Request::GetGameState(player_b),
+    )) {
+        (_, Response::GameState(state)) => state,
+        r => panic!("unexpected response {:?}", r),
+    };
+    assert_eq!(state.phase, crate::GamePhase::InProgress);
+    assert_eq!(state.turn, Some(player_b));
+    assert_eq!(
+        state.opponents,
+        vec![Opponent {
+            player_id: player_a,
+            name: "a".into(),
+            ready: true,
+            sunk: vec![],
+        }]
+    );
+    assert_eq!(state.shots_fired, 1);
+    assert_eq!(
+        state.player.own_field().get(miss).unwrap(),
+        crate::Cell::Miss
+    );
+}
+
+/// Send a random, but mostly plausible, request. Ids and tokens are picked from those the server
+/// has handed out so far, so the requests get past authentication often enough to do something.
+#[cfg(test)]
+fn random_request(rng: &mut rand::rngs::StdRng, seen: &Seen) -> Request {
+    use rand::{seq::SliceRandom as _, Rng as _};
+    let token = seen
+        .tokens
+        .choose(rng)
+        .cloned()
+        .unwrap_or_else(PlayerToken::random);
+    let player_id = seen.players.choose(rng).cloned();
+    let player_id = player_id.unwrap_or(PlayerId(GameId(1), 1));
+    let game_id = seen.games.choose(rng).cloned().unwrap_or_default();
+    let location = Location::new(rng.gen_range(0, 12), rng.gen_range(0, 12));
+    let direction = *[
+        Direction::North,
+        Direction::South,
+        Direction::East,
+        Direction::West,
+    ]
+    .choose(rng)
+    .unwrap();
+    let name = "x".repeat(rng.gen_range(0, 4));
+    match rng.gen_range(0, 21) {
+        0 => Request::Hello {
+            version: rng.gen_range(0, protocol::PROTOCOL_VERSION + 2),
+            capabilities: vec![],
+        },
+        1 => Request::AddPlayer(game_id, name),
+        2 => Request::CreateGame,
+        3 => Request::CreatePrivateGame,
+        4 => Request::CreateCustomGame(GameOptions {
+            best_of: rng.gen_range(0, 9),
+            private: rng.gen(),
+            first_turn: *[
+                super::FirstTurn::Creator,
+                super::FirstTurn::Joiner,
+                super::FirstTurn::CoinFlip,
+                super::FirstTurn::Loser,
+            ]
+            .choose(rng)
+            .unwrap(),
+        }),
+        5 => match seen.join_codes.choose(rng) {
+            Some(join_code) => Request::JoinWithCode(join_code.clone(), name),
+            None => Request::JoinWithCode(JoinCode::random(), name),
+        },
+        6 => Request::JoinGame(token),
+        7 => Request::WaitForTurn(token),
+        8 => Request::Winner(game_id),
+        9 => Request::Subscribe(token),
+        10 => Request::ListOpenGames,
+        11 => Request::QuickMatch(name),
+        12 => Request::Chat(token, ChatMessage::Text(name)),
+        13 => Request::Rematch(token),
+        14..=16 => Request::PlaceShip(token, ShipId(rng.gen_range(0, 7)), location, direction),
+        17 => Request::GetGameState(player_id),
+        _ => Request::Advance(token, player_id, location),
+    }
+}
+
+/// What the server has told the clients in `test_random_requests_never_panic` so far.
+#[cfg(test)]
+#[derive(Default)]
+struct Seen {
+    tokens: Vec<PlayerToken>,
+    players: Vec<PlayerId>,
+    games: Vec<GameId>,
+    join_codes: Vec<JoinCode>,
+}
+
+#[cfg(test)]
+impl Seen {
+    fn record(&mut self, outbox: &Outbox) {
+        for (_, outbound) in outbox {
+            let response = match outbound {
+                Outbound::Send(message) => &message.response,
+                _ => continue,
+            };
+            match response {
+                Response::AddPlayer(player_id, token) | Response::QuickMatch(player_id, token) => {
+                    self.players.push(*player_id);
+                    self.tokens.push(token.clone());
+                    self.games.push(player_id.game_id());
+                }
+                Response::CreateGame(game_id) => self.games.push(*game_id),
+                Response::CreatePrivateGame(game_id, join_code) => {
+                    self.games.push(*game_id);
+                    self.join_codes.push(join_code.clone());
+                }
+                Response::Event(GameEvent::Rematch { players, .. }) => {
+                    self.players.extend(players.iter().map(|&(_, new)| new));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[test]
+fn test_random_requests_never_panic() {
+    use rand::{rngs::StdRng, seq::SliceRandom as _, Rng as _, SeedableRng as _};
+
+    let run = |seed| {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut server = GameServer::new();
+        let mut seen = Seen::default();
+        let mut connections = vec![];
+        for _ in 0..500 {
+            match rng.gen_range(0, 20) {
+                0 => connections.push(greeted_connection(&mut server)),
+                1 => connections.extend(server.connect()),
+                2 if !connections.is_empty() => {
+                    let connection = connections.swap_remove(rng.gen_range(0, connections.len()));
+                    server.disconnect(connection);
+                }
+                3 => {
+                    let now = server.now + Duration::from_secs(rng.gen_range(0, 2 * 60 * 60));
+                    server.tick(now);
+                }
+                _ => {
+                    if let Some(&connection) = connections.choose(&mut rng) {
+                        let request = random_request(&mut rng, &seen);
+                        let outbox = self::request(&mut server, connection, request);
+                        seen.record(&outbox);
+                    }
+                }
+            }
+        }
+    };
 
-        receiver
+    for seed in 0..200 {
+        let result = std::panic::catch_unwind(|| run(seed));
+        assert!(result.is_ok(), "panicked with seed {}", seed);
     }
 }
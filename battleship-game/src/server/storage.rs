@@ -0,0 +1,237 @@
+// copyright 2020 Remi Bernotavicius
+
+//! Keeping games around across server restarts.
+
+use crate::{Game, GameId, PlayerId, PlayerToken};
+use log::info;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead as _, Read as _, Seek as _, Write as _};
+use std::path::{Path, PathBuf};
+
+/// Everything needed to bring a game back after a restart.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GameRecord {
+    pub game: Game,
+    pub tokens: Vec<(PlayerToken, PlayerId)>,
+}
+
+pub trait Storage: Send {
+    /// Every game saved and not removed since.
+    fn load(&mut self) -> io::Result<Vec<GameRecord>>;
+
+    /// The highest id of any game ever saved, including the ones removed since, so no id is
+    /// handed out twice.
+    fn last_game_id(&mut self) -> io::Result<GameId>;
+
+    /// Record the current state of some games.
+    fn save(&mut self, records: &[GameRecord]) -> io::Result<()>;
+
+    fn remove(&mut self, game_id: GameId) -> io::Result<()>;
+
+    /// Called now and then with every game, so the storage can compact what it has written.
+    fn snapshot(&mut self, records: &[GameRecord], last_game_id: GameId) -> io::Result<()>;
+}
+
+#[derive(Serialize, Deserialize)]
+enum JournalEntry<R> {
+    Save(R),
+    Remove(GameId),
+}
+
+const SNAPSHOT: &str = "snapshot.json";
+const JOURNAL: &str = "journal.json";
+const LAST_GAME_ID: &str = "last_game_id.json";
+
+/// Keeps games in a directory as a snapshot of every game, plus a journal of the changes made
+/// since the snapshot was taken. Both files hold one JSON value per line. The last game id is
+/// kept to the side, as its game may be gone from both.
+pub struct FileStorage {
+    directory: PathBuf,
+    journal: File,
+}
+
+impl FileStorage {
+    pub fn open(directory: impl AsRef<Path>) -> io::Result<Self> {
+        let directory = directory.as_ref().to_owned();
+        fs::create_dir_all(&directory)?;
+        let mut journal = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(directory.join(JOURNAL))?;
+
+        // A crash can leave the last line half written; make sure it doesn't swallow the next one.
+        let length = journal.metadata()?.len();
+        if length > 0 {
+            let mut last = [0];
+            journal.seek(io::SeekFrom::Start(length - 1))?;
+            journal.read_exact(&mut last)?;
+            if last != *b"\n" {
+                journal.write_all(b"\n")?;
+            }
+        }
+
+        Ok(Self { directory, journal })
+    }
+
+    fn append(&mut self, entries: &[JournalEntry<&GameRecord>]) -> io::Result<()> {
+        let mut lines = vec![];
+        for entry in entries {
+            serde_json::to_writer(&mut lines, entry)?;
+            lines.push(b'\n');
+        }
+        self.journal.write_all(&lines)?;
+        self.journal.sync_data()
+    }
+
+    /// Write the file by writing it to the side and renaming it into place, so there is always
+    /// one whole copy of it.
+    fn replace(
+        &self,
+        name: &str,
+        write: impl FnOnce(&mut dyn io::Write) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let partial = self.directory.join(format!("{}.partial", name));
+        let mut file = io::BufWriter::new(File::create(&partial)?);
+        write(&mut file)?;
+        file.into_inner()?.sync_all()?;
+        fs::rename(&partial, self.directory.join(name))
+    }
+}
+
+/// Read one value per line, skipping any lines which were only partly written.
+fn read_lines<T: DeserializeOwned>(path: &Path) -> io::Result<Vec<T>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut values = vec![];
+    for line in io::BufReader::new(file).lines() {
+        match serde_json::from_str(&line?) {
+            Ok(value) => values.push(value),
+            Err(e) => info!("skipping corrupt line in {}: {}", path.display(), e),
+        }
+    }
+    Ok(values)
+}
+
+impl Storage for FileStorage {
+    fn load(&mut self) -> io::Result<Vec<GameRecord>> {
+        let mut games = HashMap::new();
+        for record in read_lines::<GameRecord>(&self.directory.join(SNAPSHOT))? {
+            games.insert(record.game.id(), record);
+        }
+        for entry in read_lines::<JournalEntry<GameRecord>>(&self.directory.join(JOURNAL))? {
+            match entry {
+                JournalEntry::Save(record) => {
+                    games.insert(record.game.id(), record);
+                }
+                JournalEntry::Remove(game_id) => {
+                    games.remove(&game_id);
+                }
+            }
+        }
+        Ok(games.into_values().collect())
+    }
+
+    fn last_game_id(&mut self) -> io::Result<GameId> {
+        let mut ids = read_lines::<GameId>(&self.directory.join(LAST_GAME_ID))?;
+        for record in read_lines::<GameRecord>(&self.directory.join(SNAPSHOT))? {
+            ids.push(record.game.id());
+        }
+        for entry in read_lines::<JournalEntry<GameRecord>>(&self.directory.join(JOURNAL))? {
+            ids.push(match entry {
+                JournalEntry::Save(record) => record.game.id(),
+                JournalEntry::Remove(game_id) => game_id,
+            });
+        }
+        Ok(ids.into_iter().max().unwrap_or_default())
+    }
+
+    fn save(&mut self, records: &[GameRecord]) -> io::Result<()> {
+        let entries: Vec<_> = records.iter().map(JournalEntry::Save).collect();
+        self.append(&entries)
+    }
+
+    fn remove(&mut self, game_id: GameId) -> io::Result<()> {
+        self.append(&[JournalEntry::Remove(game_id)])
+    }
+
+    fn snapshot(&mut self, records: &[GameRecord], last_game_id: GameId) -> io::Result<()> {
+        // The removed games' ids are about to leave the journal, so the last one is kept first.
+        self.replace(LAST_GAME_ID, |file| {
+            serde_json::to_writer(&mut *file, &last_game_id)?;
+            file.write_all(b"\n")
+        })?;
+        self.replace(SNAPSHOT, |file| {
+            for record in records {
+                serde_json::to_writer(&mut *file, record)?;
+                file.write_all(b"\n")?;
+            }
+            Ok(())
+        })?;
+
+        // Everything in the journal is in the snapshot now. Should we crash before this, the
+        // journal is just replayed on top of the snapshot.
+        self.journal.set_len(0)?;
+        self.journal.sync_all()
+    }
+}
+
+#[test]
+fn test_file_storage_reload() {
+    let directory = tempfile::tempdir().unwrap();
+    let record = |id| GameRecord {
+        game: Game::new(GameId(id)),
+        tokens: vec![],
+    };
+
+    let mut storage = FileStorage::open(directory.path()).unwrap();
+    storage.save(&[record(1), record(2)]).unwrap();
+    storage
+        .snapshot(&[record(1), record(2)], GameId(2))
+        .unwrap();
+    storage.save(&[record(3)]).unwrap();
+    storage.remove(GameId(1)).unwrap();
+    drop(storage);
+
+    // As if we crashed halfway through writing an entry.
+    let journal = directory.path().join(JOURNAL);
+    let mut file = OpenOptions::new().append(true).open(&journal).unwrap();
+    file.write_all(br#"{"Save":{"game""#).unwrap();
+    drop(file);
+
+    let mut storage = FileStorage::open(directory.path()).unwrap();
+    storage.save(&[record(4)]).unwrap();
+    let mut ids: Vec<_> = storage
+        .load()
+        .unwrap()
+        .iter()
+        .map(|r| r.game.id())
+        .collect();
+    ids.sort();
+    assert_eq!(ids, [GameId(2), GameId(3), GameId(4)]);
+}
+
+#[test]
+fn test_file_storage_remembers_removed_ids() {
+    let directory = tempfile::tempdir().unwrap();
+    let record = |id| GameRecord {
+        game: Game::new(GameId(id)),
+        tokens: vec![],
+    };
+
+    let mut storage = FileStorage::open(directory.path()).unwrap();
+    storage.save(&[record(1), record(2)]).unwrap();
+    storage.remove(GameId(2)).unwrap();
+    assert_eq!(storage.last_game_id().unwrap(), GameId(2));
+
+    // Once the journal is gone, the snapshot no longer mentions the removed game.
+    storage.snapshot(&[record(1)], GameId(2)).unwrap();
+    drop(storage);
+    let mut storage = FileStorage::open(directory.path()).unwrap();
+    assert_eq!(storage.last_game_id().unwrap(), GameId(2));
+}
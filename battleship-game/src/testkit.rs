@@ -0,0 +1,292 @@
+// copyright 2020 Remi Bernotavicius
+
+//! Run a server and its clients in one process, talking over in-memory connections instead of
+//! sockets.
+
+use crate::client::blocking::{self, BlockingGameClient};
+use crate::server::blocking::{BlockingGameServer, Close, Listener, SplitStream};
+use crate::server::{GameServer, Limits};
+use crate::GameOptions;
+use crossbeam_utils::thread;
+use std::io;
+use std::sync::{mpsc, Mutex, PoisonError};
+
+/// The receiving half of an in-memory connection. It reaches the end once the other side's
+/// `MemoryWriter` is dropped.
+pub struct MemoryReader {
+    receiver: mpsc::Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl io::Read for MemoryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            match self.receiver.recv() {
+                Ok(data) => {
+                    self.buffer = data;
+                    self.position = 0;
+                }
+                Err(mpsc::RecvError) => return Ok(0),
+            }
+        }
+        let remaining = &self.buffer[self.position..];
+        let len = remaining.len().min(buf.len());
+        buf[..len].copy_from_slice(&remaining[..len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// The sending half of an in-memory connection.
+pub struct MemoryWriter {
+    sender: mpsc::Sender<Vec<u8>>,
+}
+
+impl io::Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sender
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Close for MemoryWriter {
+    /// The other side's reader reaches the end once this is dropped, which is soon enough here.
+    fn close(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// One end of an in-memory connection.
+pub struct MemoryStream {
+    reader: MemoryReader,
+    writer: MemoryWriter,
+}
+
+impl io::Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl io::Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl SplitStream for MemoryStream {
+    type Reader = MemoryReader;
+    type Writer = MemoryWriter;
+
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+        Ok((self.reader, self.writer))
+    }
+}
+
+fn half_pipe() -> (MemoryWriter, MemoryReader) {
+    let (sender, receiver) = mpsc::channel();
+    let reader = MemoryReader {
+        receiver,
+        buffer: vec![],
+        position: 0,
+    };
+    (MemoryWriter { sender }, reader)
+}
+
+/// A connection between two in-memory streams. What is written to one is read from the other.
+pub fn pipe() -> (MemoryStream, MemoryStream) {
+    let (a_writer, b_reader) = half_pipe();
+    let (b_writer, a_reader) = half_pipe();
+    let a = MemoryStream {
+        reader: a_reader,
+        writer: a_writer,
+    };
+    let b = MemoryStream {
+        reader: b_reader,
+        writer: b_writer,
+    };
+    (a, b)
+}
+
+pub type TestClient = BlockingGameClient<MemoryStream>;
+
+/// Opens connections to a `MemoryListener`.
+#[derive(Clone)]
+pub struct Connector {
+    sender: mpsc::Sender<MemoryStream>,
+}
+
+impl Connector {
+    pub fn connect(&self) -> io::Result<MemoryStream> {
+        let (client, server) = pipe();
+        self.sender
+            .send(server)
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        Ok(client)
+    }
+
+    /// A client which reconnects through this connector if its connection is lost.
+    pub fn client(&self) -> blocking::Result<TestClient> {
+        let connector = self.clone();
+        BlockingGameClient::connect_with(move || connector.connect())
+    }
+}
+
+/// Accepts the connections made through its `Connector`s, until they are all dropped.
+pub struct MemoryListener {
+    connections: Mutex<mpsc::Receiver<MemoryStream>>,
+}
+
+pub fn listener() -> (MemoryListener, Connector) {
+    let (sender, receiver) = mpsc::channel();
+    let listener = MemoryListener {
+        connections: Mutex::new(receiver),
+    };
+    (listener, Connector { sender })
+}
+
+pub struct Incoming<'a> {
+    listener: &'a MemoryListener,
+}
+
+impl Iterator for Incoming<'_> {
+    type Item = io::Result<MemoryStream>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let connections = self
+            .listener
+            .connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        connections.recv().ok().map(Ok)
+    }
+}
+
+impl<'a> Listener<'a> for MemoryListener {
+    type Incoming = Incoming<'a>;
+    type Stream = MemoryStream;
+
+    fn incoming(&'a self) -> Self::Incoming {
+        Incoming { listener: self }
+    }
+}
+
+/// A server for scripted clients. It has no request rate limit, because without ticks it would
+/// count every request as arriving in the same instant.
+pub fn server() -> BlockingGameServer {
+    let mut game = GameServer::new();
+    game.set_limits(Limits {
+        max_requests_per_second: usize::MAX,
+        ..Limits::default()
+    });
+    BlockingGameServer::with_game_server(game)
+}
+
+pub type Script<'a, T> = Box<dyn FnOnce(&mut TestClient) -> T + Send + 'a>;
+
+/// Play a game on `server` with a client for each of `scripts`. The first client creates the game
+/// with `options` and the rest join it in order, as "player 1", "player 2" and so on. Then each
+/// script runs on its own thread with its client. Returns what the scripts returned, once they
+/// have all finished and every client has disconnected.
+///
+/// Panics if a client can't join the game. The server isn't ticked, so nothing happens in the game
+/// unless a client does it. If a script panics, its client disconnects and the others get
+/// `GameEvent::OpponentDisconnected`, and any that go on waiting for events never finish.
+pub fn play<T: Send>(
+    server: &BlockingGameServer,
+    options: GameOptions,
+    scripts: Vec<Script<'_, T>>,
+) -> Vec<T> {
+    let (listener, connector) = listener();
+    thread::scope(|scope| {
+        scope.spawn(|scope| {
+            for connection in listener.incoming().flatten() {
+                scope.spawn(move |_| server.process_requests(connection));
+            }
+        });
+
+        let mut clients: Vec<TestClient> = vec![];
+        for number in 1..=scripts.len() {
+            let name = format!("player {}", number);
+            let mut client = connector.client().expect("failed to connect");
+            match clients.first() {
+                None => client.create_custom_game(&name, options.clone()),
+                Some(first) => match first.join_code() {
+                    Some(join_code) => client.join_with_code(join_code.clone(), &name),
+                    None => client.join_game(first.game_id(), &name),
+                },
+            }
+            .expect("failed to join the game");
+            clients.push(client);
+        }
+        drop(connector);
+
+        let players: Vec<_> = scripts
+            .into_iter()
+            .zip(clients)
+            .map(|(script, mut client)| scope.spawn(move |_| script(&mut client)))
+            .collect();
+        players
+            .into_iter()
+            .map(|player| player.join().unwrap())
+            .collect()
+    })
+    .unwrap()
+}
+
+#[test]
+fn test_play() {
+    use crate::protocol::GameEvent;
+    use crate::{Direction, FirstTurn, Location, Play as _, PlayerId};
+
+    // Both players line their ships up in the same rows and shoot along the rows in the same
+    // order, so whoever shoots first wins.
+    fn script(client: &mut TestClient) -> (PlayerId, PlayerId) {
+        let me = client.player_id();
+        let mut ship_ids: Vec<_> = client.get_player(me).unwrap().ships().into_keys().collect();
+        ship_ids.sort();
+        for (row, ship_id) in ship_ids.into_iter().enumerate() {
+            let location = Location { column: 0, row };
+            client
+                .place_ship(me, ship_id, location, Direction::East)
+                .unwrap();
+        }
+
+        let mut targets =
+            (0..10).flat_map(|row| (0..10).map(move |column| Location { column, row }));
+        loop {
+            match client.next_event().unwrap() {
+                GameEvent::Turn(player_id) if player_id == me => {
+                    let opponent = client.other_player_ids()[0];
+                    client
+                        .advance(me, opponent, targets.next().unwrap())
+                        .unwrap();
+                }
+                GameEvent::GameOver { winner } => return (me, winner),
+                _ => {}
+            }
+        }
+    }
+
+    let server = server();
+    let options = GameOptions {
+        first_turn: FirstTurn::Creator,
+        ..GameOptions::default()
+    };
+    let scripts: Vec<Script<'_, _>> = vec![Box::new(script), Box::new(script)];
+    let results = play(&server, options, scripts);
+    let (creator, winner) = results[0];
+    assert_eq!(winner, creator);
+    assert_eq!(results[1].1, creator);
+}
@@ -0,0 +1,233 @@
+// copyright 2020 Remi Bernotavicius
+
+//! Messages are sent as frames: a four byte big-endian length followed by that many bytes of
+//! payload. Every connection starts out encoding the payload as JSON, and switches to the codec
+//! picked by `Codec::negotiate` once the `Hello` exchange is over.
+
+use super::protocol::{self, Capability};
+use bincode::Options as _;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fmt, io};
+
+/// The largest frame accepted unless configured otherwise.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+
+const LENGTH_SIZE: usize = 4;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Bincode(bincode::Error),
+    FrameTooLarge(usize),
+}
+
+impl Error {
+    /// Whether the stream is still usable after this error, i.e. only the contents of the frame
+    /// were bad.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, Self::Json(_) | Self::Bincode(_))
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Self::Bincode(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(fmt, "{}", e),
+            Self::Json(e) => write!(fmt, "malformed JSON message: {}", e),
+            Self::Bincode(e) => write!(fmt, "malformed bincode message: {}", e),
+            Self::FrameTooLarge(size) => write!(fmt, "frame of {} bytes is too large", size),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Json,
+    Bincode,
+}
+
+impl Codec {
+    /// The codec to use after the handshake, given the capabilities the other side advertised.
+    pub fn negotiate(theirs: &[Capability]) -> Self {
+        let bincode = Capability::Bincode;
+        if protocol::CAPABILITIES.contains(&bincode) && theirs.contains(&bincode) {
+            Self::Bincode
+        } else {
+            Self::Json
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, message: &T) -> Result<Vec<u8>> {
+        match self {
+            Self::Json => Ok(serde_json::to_vec(message)?),
+            Self::Bincode => Ok(bincode::DefaultOptions::new().serialize(message)?),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T> {
+        match self {
+            Self::Json => Ok(serde_json::from_slice(payload)?),
+            Self::Bincode => Ok(bincode::DefaultOptions::new().deserialize(payload)?),
+        }
+    }
+}
+
+/// Prefix the payload with its length.
+pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(LENGTH_SIZE + payload.len());
+    frame.extend(&(payload.len() as u32).to_be_bytes());
+    frame.extend(payload);
+    frame
+}
+
+/// Remove the first complete frame from the front of the buffer and return its payload, or `None`
+/// if the buffer doesn't contain a whole frame yet.
+pub fn decode_frame(buffer: &mut Vec<u8>, max_frame_size: usize) -> Result<Option<Vec<u8>>> {
+    if buffer.len() < LENGTH_SIZE {
+        return Ok(None);
+    }
+    let mut length = [0; LENGTH_SIZE];
+    length.copy_from_slice(&buffer[..LENGTH_SIZE]);
+    let length = u32::from_be_bytes(length) as usize;
+    if length > max_frame_size {
+        return Err(Error::FrameTooLarge(length));
+    }
+    if buffer.len() < LENGTH_SIZE + length {
+        return Ok(None);
+    }
+    let rest = buffer.split_off(LENGTH_SIZE + length);
+    let mut frame = std::mem::replace(buffer, rest);
+    Ok(Some(frame.split_off(LENGTH_SIZE)))
+}
+
+/// Sends and receives framed messages over a stream.
+pub struct FramedStream<S> {
+    stream: S,
+    codec: Codec,
+    max_frame_size: usize,
+}
+
+impl<S> FramedStream<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            codec: Codec::Json,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.max_frame_size = max_frame_size;
+    }
+}
+
+impl<S: io::Write> FramedStream<S> {
+    pub fn send<T: Serialize>(&mut self, message: &T) -> Result<()> {
+        let payload = self.codec.encode(message)?;
+        if payload.len() > self.max_frame_size {
+            return Err(Error::FrameTooLarge(payload.len()));
+        }
+        // Written all at once so message-based streams get the whole frame in one message.
+        self.stream.write_all(&encode_frame(&payload))?;
+        self.stream.flush()?;
+        Ok(())
+    }
+}
+
+impl<S: io::Read> FramedStream<S> {
+    pub fn receive<T: DeserializeOwned>(&mut self) -> Result<T> {
+        let mut length = [0; LENGTH_SIZE];
+        self.stream.read_exact(&mut length)?;
+        let length = u32::from_be_bytes(length) as usize;
+        if length > self.max_frame_size {
+            return Err(Error::FrameTooLarge(length));
+        }
+
+        let mut payload = vec![0; length];
+        self.stream.read_exact(&mut payload)?;
+        self.codec.decode(&payload)
+    }
+}
+
+#[test]
+fn test_decode_frame() {
+    let mut buffer = encode_frame(b"hello");
+    buffer.extend(&encode_frame(b"")[..]);
+    buffer.extend(&encode_frame(b"partial")[..6]);
+
+    assert_eq!(
+        decode_frame(&mut buffer, 10).unwrap(),
+        Some(b"hello".to_vec())
+    );
+    assert_eq!(decode_frame(&mut buffer, 10).unwrap(), Some(vec![]));
+    assert_eq!(decode_frame(&mut buffer, 10).unwrap(), None);
+    assert_eq!(buffer.len(), 6);
+    assert!(matches!(
+        decode_frame(&mut buffer, 4),
+        Err(Error::FrameTooLarge(7))
+    ));
+}
+
+#[test]
+fn test_framed_stream_round_trip() {
+    use super::protocol::{ClientMessage, Request, RequestId};
+
+    for &codec in &[Codec::Json, Codec::Bincode] {
+        let mut stream = FramedStream::new(vec![]);
+        stream.set_codec(codec);
+        let message = ClientMessage {
+            id: RequestId(7),
+            request: Request::AddPlayer(crate::GameId(3), "remi".into()),
+        };
+        stream.send(&message).unwrap();
+        stream.send(&message).unwrap();
+
+        let mut stream = FramedStream {
+            stream: &stream.stream[..],
+            codec,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        };
+        for _ in 0..2 {
+            match stream.receive().unwrap() {
+                ClientMessage {
+                    id: RequestId(7),
+                    request: Request::AddPlayer(crate::GameId(3), name),
+                } => assert_eq!(name, "remi"),
+                m => panic!("unexpected message {:?}", m),
+            }
+        }
+        assert!(matches!(
+            stream.receive::<ClientMessage>(),
+            Err(Error::Io(_))
+        ));
+    }
+}
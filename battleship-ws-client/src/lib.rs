@@ -1,13 +1,15 @@
 // copyright 2020 Remi Bernotavicius
 use battleship_game::client::{ClientResponse, GameClient};
-use battleship_game::protocol::{Request, Response};
+use battleship_game::protocol::{
+    self, ChatMessage, ClientMessage, Emote, GameEvent, OpenGame, Request, ServerMessage,
+};
+use battleship_game::transport::{self, DEFAULT_MAX_FRAME_SIZE};
 use battleship_game::{
-    row_to_letter, BattleField, Cell, Direction, GameId, Location, PlayerId, Ship, ShipId,
+    row_to_letter, AttackResult, BattleField, Cell, Direction, GameId, GameOptions, GamePhase,
+    JoinCode, Location, PlayerToken, Ship, ShipId,
 };
-use serde::Deserialize as _;
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::io;
 use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
@@ -41,13 +43,20 @@ fn request_animation_frame(f: &Closure<dyn FnMut()>) {
 
 enum GameState {
     Connecting,
+    WaitingForHello(WebSocket),
+    WaitingForOpenGames(WebSocket),
+    Lobby(Vec<OpenGame>, WebSocket),
     PlacingShip(ShipId, Direction, WebSocket),
+    WaitingForShipPlacement(WebSocket),
     WaitingForGameCreate(WebSocket),
     WaitingForGameJoin(WebSocket),
+    WaitingForState(WebSocket),
     WaitingForPlayerAdd(WebSocket),
-    WaitingForTurn(WebSocket),
+    WaitingForOpponent(WebSocket),
     WaitingForAttackResult(WebSocket),
     MyTurn(WebSocket),
+    GameOver(WebSocket),
+    WaitingForRematch(WebSocket),
     Error,
 }
 
@@ -55,6 +64,99 @@ impl GameState {
     fn take(&mut self) -> Self {
         std::mem::replace(self, Self::Error)
     }
+
+    fn socket(&self) -> Option<&WebSocket> {
+        match self {
+            Self::WaitingForHello(socket)
+            | Self::WaitingForOpenGames(socket)
+            | Self::Lobby(_, socket)
+            | Self::PlacingShip(_, _, socket)
+            | Self::WaitingForShipPlacement(socket)
+            | Self::WaitingForGameCreate(socket)
+            | Self::WaitingForGameJoin(socket)
+            | Self::WaitingForState(socket)
+            | Self::WaitingForPlayerAdd(socket)
+            | Self::WaitingForOpponent(socket)
+            | Self::WaitingForAttackResult(socket)
+            | Self::MyTurn(socket)
+            | Self::GameOver(socket)
+            | Self::WaitingForRematch(socket) => Some(socket),
+            Self::Connecting | Self::Error => None,
+        }
+    }
+}
+
+/// The chat panel sits to the right of the fields, with the emote buttons at the bottom.
+const CHAT_X: f64 = 1090.0;
+const CHAT_TOP: f64 = 55.0;
+const CHAT_LINE_HEIGHT: f64 = 22.0;
+const CHAT_LINES: usize = 16;
+const CHAT_LINE_LENGTH: usize = 40;
+const EMOTE_TOP: f64 = CHAT_TOP + (CHAT_LINES + 1) as f64 * CHAT_LINE_HEIGHT + 10.0;
+const EMOTE_HEIGHT: f64 = 28.0;
+const EMOTE_WIDTH: f64 = 140.0;
+
+/// The emote button at the given position, if any.
+fn emote_at(x: u32, y: u32) -> Option<Emote> {
+    let (x, y) = (x as f64, y as f64);
+    if !(CHAT_X..=CHAT_X + EMOTE_WIDTH).contains(&x) || y < EMOTE_TOP {
+        return None;
+    }
+    Emote::ALL
+        .get(((y - EMOTE_TOP) / EMOTE_HEIGHT) as usize)
+        .cloned()
+}
+
+const LOBBY_TOP: f64 = 55.0;
+const LOBBY_ROW_HEIGHT: f64 = 40.0;
+
+/// What clicking on a row of the lobby does.
+enum LobbyChoice {
+    New(GameOptions),
+    QuickMatch,
+    Refresh,
+    Join(GameId),
+}
+
+/// The rows of the lobby, top to bottom.
+fn lobby_choices(open_games: &[OpenGame]) -> Vec<(String, LobbyChoice)> {
+    let mut choices = vec![
+        ("New game".into(), LobbyChoice::New(GameOptions::default())),
+        (
+            "New best of 3 series".into(),
+            LobbyChoice::New(GameOptions {
+                best_of: 3,
+                ..GameOptions::default()
+            }),
+        ),
+        (
+            "New private game".into(),
+            LobbyChoice::New(GameOptions {
+                private: true,
+                ..GameOptions::default()
+            }),
+        ),
+        ("Quick match".into(), LobbyChoice::QuickMatch),
+        ("Refresh".into(), LobbyChoice::Refresh),
+    ];
+    for open_game in open_games {
+        let text = format!(
+            "Join {} in game {} ({})",
+            open_game.host, open_game.game_id, open_game.rules
+        );
+        choices.push((text, LobbyChoice::Join(open_game.game_id)));
+    }
+    choices
+}
+
+/// The lobby row at the given height, if any.
+fn lobby_row(y: u32) -> Option<usize> {
+    let row = (y as f64 - LOBBY_TOP) / LOBBY_ROW_HEIGHT;
+    if row >= 0.0 {
+        Some(row as usize)
+    } else {
+        None
+    }
 }
 
 struct RenderableField {
@@ -205,6 +307,11 @@ struct Game {
     message: Option<(String, MessageLevel)>,
     state: GameState,
     fields: Option<GameFields>,
+    enemy_attack: Option<AttackResult>,
+    /// Chat so far, already wrapped to fit the panel.
+    chat: Vec<String>,
+    /// The chat message being typed.
+    draft: String,
 }
 
 impl Game {
@@ -222,6 +329,9 @@ impl Game {
             message: None,
             state: GameState::Connecting,
             fields: None,
+            enemy_attack: None,
+            chat: vec![],
+            draft: String::new(),
         }
     }
 
@@ -237,36 +347,236 @@ impl Game {
                 use MessageLevel::{Info, Warn};
                 let color = if result.is_hit() { Warn } else { Info };
                 match self.state.take() {
-                    GameState::WaitingForTurn(socket) => {
-                        self.message(format!("Enemy attack: {}, your turn", &result), color);
-                        self.state = GameState::MyTurn(socket);
-                    }
                     GameState::WaitingForAttackResult(socket) => {
                         self.message(format!("Your attack: {}, waiting for enemy", result), color);
                         self.wait_for_turn(socket);
                     }
-                    _ => (),
+                    s => self.state = s,
+                }
+            }
+            ClientResponse::Events(events) => {
+                for event in events {
+                    self.on_event(event);
                 }
             }
+            ClientResponse::OpenGames(open_games) => match self.state.take() {
+                GameState::WaitingForOpenGames(socket) => {
+                    if open_games.is_empty() {
+                        self.message("No open games", MessageLevel::Info);
+                    } else {
+                        self.message("Pick a game to join", MessageLevel::Info);
+                    }
+                    self.state = GameState::Lobby(open_games, socket);
+                }
+                s => self.state = s,
+            },
             ClientResponse::None => match self.state.take() {
+                GameState::WaitingForHello(socket) => self.on_hello(socket),
                 GameState::WaitingForGameCreate(socket) => {
                     self.add_player(socket);
                 }
-                GameState::WaitingForGameJoin(socket) | GameState::WaitingForPlayerAdd(socket) => {
+                GameState::WaitingForGameJoin(socket) => {
+                    let request = self.client.get_game_state();
+                    if self.send_player_request(request, &socket) {
+                        self.state = GameState::WaitingForState(socket);
+                    } else {
+                        self.list_open_games(socket);
+                    }
+                }
+                GameState::WaitingForPlayerAdd(socket) => {
                     self.on_player_join();
+                    let request = self.client.subscribe();
+                    self.send_player_request(request, &socket);
                     self.try_to_place_ship(socket);
                 }
-                GameState::PlacingShip(_, _, socket) => self.try_to_place_ship(socket),
-                GameState::WaitingForTurn(socket) => {
-                    self.message("Your turn", MessageLevel::Info);
-                    self.state = GameState::MyTurn(socket);
+                GameState::WaitingForShipPlacement(socket) => self.try_to_place_ship(socket),
+                s => self.state = s,
+            },
+            ClientResponse::ChatSent(message) => self.add_chat_line("You", &message),
+            ClientResponse::GameState(game_state) => match self.state.take() {
+                GameState::WaitingForState(socket) => self.on_rejoin(game_state.phase, socket),
+                s => self.state = s,
+            },
+            ClientResponse::Rejected(request, error) => {
+                self.message(error.to_string(), MessageLevel::Error);
+                match (request, self.state.take()) {
+                    (
+                        Request::PlaceShip(_, ship_id, _, direction),
+                        GameState::PlacingShip(_, _, socket),
+                    )
+                    | (
+                        Request::PlaceShip(_, ship_id, _, direction),
+                        GameState::WaitingForShipPlacement(socket),
+                    ) => {
+                        self.state = GameState::PlacingShip(ship_id, direction, socket);
+                    }
+                    (Request::Advance(..), GameState::WaitingForAttackResult(socket)) => {
+                        self.state = GameState::MyTurn(socket);
+                    }
+                    // Somebody else may have taken the game, so go back to the lobby.
+                    (Request::AddPlayer(..), GameState::WaitingForPlayerAdd(socket))
+                    | (Request::QuickMatch(_), GameState::WaitingForPlayerAdd(socket)) => {
+                        self.list_open_games(socket);
+                    }
+                    (_, s) => self.state = s,
                 }
-                _ => (),
+            }
+            _ => (),
+        }
+    }
+
+    /// Start over from the server's view of the game we rejoined. Anything worth telling the
+    /// player about arrives with the events that follow.
+    fn on_rejoin(&mut self, phase: GamePhase, socket: WebSocket) {
+        self.enemy_attack = None;
+        self.on_player_join();
+        let request = self.client.subscribe();
+        self.send_player_request(request, &socket);
+        match phase {
+            GamePhase::Over { .. } => self.state = GameState::GameOver(socket),
+            _ => self.try_to_place_ship(socket),
+        }
+    }
+
+    fn on_event(&mut self, event: GameEvent) {
+        use MessageLevel::{Info, Warn};
+        // Whatever is still on its way from a game we are no longer in can be ignored.
+        let player_id = match self.client.joined_player_id() {
+            Some(player_id) => player_id,
+            None => return,
+        };
+        match event {
+            GameEvent::OpponentJoined(_, name) => {
+                self.message(format!("{} joined the game", name), Info)
+            }
+            GameEvent::OpponentReady(_) => self.message("Enemy placed their ships", Info),
+            GameEvent::ShotFired { target, result, .. } if target == player_id => {
+                self.enemy_attack = Some(result);
+            }
+            GameEvent::Turn(id) if id == player_id => match self.state.take() {
+                GameState::WaitingForOpponent(socket) => self.wait_for_turn(socket),
+                s => self.state = s,
+            },
+            GameEvent::GameOver { winner } => {
+                self.state = match self.state.take() {
+                    GameState::WaitingForOpponent(socket)
+                    | GameState::MyTurn(socket)
+                    | GameState::WaitingForAttackResult(socket) => GameState::GameOver(socket),
+                    s => s,
+                };
+                if winner == player_id {
+                    self.message("You win, click here for a rematch", Info);
+                } else {
+                    self.message("You lose, click here for a rematch", Warn);
+                }
+            }
+            GameEvent::RematchOffered(_) => {
+                if let GameState::GameOver(_) = &self.state {
+                    self.message("Enemy wants a rematch, click here to accept", Info);
+                }
+            }
+            GameEvent::Rematch { .. } => match self.state.take() {
+                GameState::GameOver(socket) | GameState::WaitingForRematch(socket) => {
+                    self.enemy_attack = None;
+                    self.on_player_join();
+                    self.try_to_place_ship(socket);
+                }
+                s => self.state = s,
             },
+            GameEvent::OpponentDisconnected(_) => {
+                self.message("Enemy disconnected, waiting for them to reconnect", Warn)
+            }
+            GameEvent::FirstTurn { player, chosen_by } => {
+                let who = if player == player_id {
+                    "You shoot"
+                } else {
+                    "Enemy shoots"
+                };
+                self.add_notice(&format!("{} first, {}", who, chosen_by));
+            }
+            GameEvent::Chat { name, message, .. } => self.add_chat_line(&name, &message),
             _ => (),
         }
     }
 
+    fn add_chat_line(&mut self, name: &str, message: &ChatMessage) {
+        self.add_notice(&format!("{}: {}", name, message));
+    }
+
+    /// Add a line to the chat panel, wrapped to fit.
+    fn add_notice(&mut self, text: &str) {
+        let line: Vec<_> = text.chars().collect();
+        for chunk in line.chunks(CHAT_LINE_LENGTH) {
+            self.chat.push(chunk.iter().collect());
+        }
+    }
+
+    fn send_chat(&mut self, message: ChatMessage) {
+        if let Some(socket) = self.state.socket().cloned() {
+            let request = self.client.chat(message);
+            // Shown once the server has passed it on.
+            self.send_player_request(request, &socket);
+        }
+    }
+
+    fn on_key(&mut self, key: &str) {
+        // Nothing to chat about until we are in a game.
+        if self.fields.is_none() {
+            return;
+        }
+        match key {
+            "Enter" if !self.draft.trim().is_empty() => {
+                let text = std::mem::take(&mut self.draft);
+                self.send_chat(ChatMessage::Text(text));
+            }
+            "Backspace" => {
+                self.draft.pop();
+            }
+            key if key.chars().count() == 1
+                && self.draft.chars().count() < protocol::MAX_CHAT_LENGTH =>
+            {
+                self.draft.push_str(key)
+            }
+            _ => {}
+        }
+    }
+
+    fn render_chat(&self) {
+        let context = &self.drawing_context;
+        context.set_font("16px arial");
+        context.set_fill_style(&JsValue::from_str("black"));
+        let start = self.chat.len().saturating_sub(CHAT_LINES);
+        for (i, line) in self.chat[start..].iter().enumerate() {
+            let y = CHAT_TOP + (i + 1) as f64 * CHAT_LINE_HEIGHT;
+            context.fill_text(line, CHAT_X, y).unwrap();
+        }
+
+        // Only the end of a long draft fits.
+        let draft: Vec<_> = self.draft.chars().collect();
+        let draft_start = draft.len().saturating_sub(CHAT_LINE_LENGTH - 2);
+        let draft: String = draft[draft_start..].iter().collect();
+        let y = CHAT_TOP + (CHAT_LINES + 1) as f64 * CHAT_LINE_HEIGHT;
+        context
+            .fill_text(&format!("> {}", draft), CHAT_X, y)
+            .unwrap();
+
+        if let (Some(series), Some(player_id)) =
+            (self.client.series(), self.client.joined_player_id())
+        {
+            let (ours, theirs) = series.score(player_id);
+            let text = format!("Series {} to {}, best of {}", ours, theirs, series.best_of);
+            context.fill_text(&text, CHAT_X, CHAT_TOP).unwrap();
+        }
+
+        for (i, emote) in Emote::ALL.iter().enumerate() {
+            let y = EMOTE_TOP + i as f64 * EMOTE_HEIGHT;
+            context.stroke_rect(CHAT_X, y, EMOTE_WIDTH, EMOTE_HEIGHT - 4.0);
+            context
+                .fill_text(&emote.to_string(), CHAT_X + 8.0, y + EMOTE_HEIGHT - 10.0)
+                .unwrap();
+        }
+    }
+
     fn on_player_join(&mut self) {
         let player = self.client.player().unwrap();
         self.fields = Some(GameFields {
@@ -286,8 +596,8 @@ impl Game {
 
         let old_href = window().location().href().unwrap();
 
-        if !old_href.contains("player=") {
-            let href = format!("{}?player={}", old_href, self.client.player_id());
+        if let (false, Ok(token)) = (old_href.contains("player="), self.client.token()) {
+            let href = format!("{}?player={}", old_href, token);
             window()
                 .history()
                 .unwrap()
@@ -302,47 +612,102 @@ impl Game {
                 .dyn_into::<web_sys::HtmlAnchorElement>()
                 .map_err(|_| ())
                 .unwrap();
-            link.set_href(&format!("{}?game={}", old_href, self.client.game_id()));
+            // Private games can't be joined by id, so share their code instead.
+            let href = match self.client.join_code() {
+                Some(join_code) => format!("{}?code={}", old_href, join_code),
+                None => format!("{}?game={}", old_href, self.client.game_id()),
+            };
+            link.set_href(&href);
             link.set_inner_html("Join game with this link");
             let body = document.body().unwrap();
             body.append_child(&link).unwrap();
         }
     }
 
+    fn player_name(&self) -> String {
+        self.url_param("name").unwrap_or_else(|| "remi".into())
+    }
+
     fn add_player(&mut self, socket: WebSocket) {
-        let request = self.client.add_player("remi");
-        self.send_request(request, &socket);
-        self.state = GameState::WaitingForPlayerAdd(socket);
+        match self.client.add_player(&self.player_name()) {
+            Ok(request) => {
+                self.send_request(request, &socket);
+                self.state = GameState::WaitingForPlayerAdd(socket);
+            }
+            Err(e) => {
+                self.message(e.to_string(), MessageLevel::Error);
+                self.list_open_games(socket);
+            }
+        }
     }
 
     fn try_to_place_ship(&mut self, socket: WebSocket) {
-        // Choose an unplaced ship
+        // Choose an unplaced ship, skipping any we already sent a placement for
         let ships = self.client.player().unwrap().ships();
-        let ship = ships.into_iter().filter(|(_, v)| !v.placed()).next();
+        let unplaced: Vec<_> = ships.into_iter().filter(|(_, v)| !v.placed()).collect();
+        let ship = unplaced
+            .iter()
+            .find(|(id, _)| !self.client.ship_placement_pending(*id));
         if let Some((ship_id, ship)) = ship {
             self.message(format!("Place {}", ship.name()), MessageLevel::Info);
-            self.state = GameState::PlacingShip(ship_id, Direction::South, socket);
+            self.state = GameState::PlacingShip(*ship_id, Direction::South, socket);
+        } else if !unplaced.is_empty() {
+            self.state = GameState::WaitingForShipPlacement(socket);
         } else {
-            self.message("Waiting for turn", MessageLevel::Info);
+            self.message("Waiting for enemy", MessageLevel::Info);
             self.wait_for_turn(socket)
         }
     }
 
-    fn send_request(&self, request: Request, socket: &WebSocket) {
-        let message = serde_json::to_string(&request).unwrap();
-        socket.send_with_str(&message).unwrap();
-        console_log!("{:?}", request);
+    /// Send a request made on behalf of our player, or say why it couldn't be made. Returns
+    /// whether it was sent.
+    fn send_player_request(
+        &mut self,
+        request: battleship_game::Result<ClientMessage>,
+        socket: &WebSocket,
+    ) -> bool {
+        match request {
+            Ok(request) => {
+                self.send_request(request, socket);
+                true
+            }
+            Err(e) => {
+                self.message(e.to_string(), MessageLevel::Error);
+                false
+            }
+        }
     }
 
+    fn send_request(&self, message: ClientMessage, socket: &WebSocket) {
+        let payload = self.client.codec().encode(&message).unwrap();
+        socket
+            .send_with_u8_array(&transport::encode_frame(&payload))
+            .unwrap();
+        console_log!("{:?}", message);
+    }
+
+    /// Our turn starts when the server says so.
     fn wait_for_turn(&mut self, socket: WebSocket) {
-        self.send_request(self.client.wait_for_turn(), &socket);
-        self.state = GameState::WaitingForTurn(socket);
+        if self.client.is_my_turn() {
+            match self.enemy_attack.take() {
+                Some(result) => {
+                    use MessageLevel::{Info, Warn};
+                    let color = if result.is_hit() { Warn } else { Info };
+                    self.message(format!("Enemy attack: {}, your turn", result), color);
+                }
+                None => self.message("Your turn", MessageLevel::Info),
+            }
+            self.state = GameState::MyTurn(socket);
+        } else {
+            self.state = GameState::WaitingForOpponent(socket);
+        }
     }
 
-    fn on_data<R: io::Read>(&mut self, reader: &mut R) -> bool {
-        if let Ok(response) =
-            Response::deserialize(&mut serde_json::Deserializer::from_reader(reader))
-        {
+    /// Handle every complete message at the front of the buffer, leaving behind any partial one.
+    fn on_data(&mut self, buffer: &mut Vec<u8>) -> transport::Result<()> {
+        while let Some(payload) = transport::decode_frame(buffer, DEFAULT_MAX_FRAME_SIZE)? {
+            // The codec can change after each message, so it has to be looked up every time.
+            let response: ServerMessage = self.client.codec().decode(&payload)?;
             console_log!("{:?}", response);
             match self.client.handle_response(response) {
                 Ok(res) => self.handle_response(res),
@@ -350,10 +715,8 @@ impl Game {
                     self.message(e.to_string(), MessageLevel::Error);
                 }
             }
-            true
-        } else {
-            false
         }
+        Ok(())
     }
 
     fn render(&mut self) {
@@ -377,6 +740,24 @@ impl Game {
                 .unwrap();
         }
 
+        if let GameState::Lobby(open_games, _) = &self.state {
+            let hovered = self.mouse_pos.and_then(|(_, y)| lobby_row(y));
+            self.drawing_context.set_font("20px arial");
+            for (row, (text, _)) in lobby_choices(open_games).iter().enumerate() {
+                let y = LOBBY_TOP + row as f64 * LOBBY_ROW_HEIGHT;
+                let color = if hovered == Some(row) {
+                    "blue"
+                } else {
+                    "black"
+                };
+                self.drawing_context
+                    .set_fill_style(&JsValue::from_str(color));
+                self.drawing_context
+                    .fill_text(text, 20.0, y + LOBBY_ROW_HEIGHT / 2.0)
+                    .unwrap();
+            }
+        }
+
         if let Some(fields) = &self.fields {
             let location = self.mouse_location(&fields.own_field);
             let player = self.client.player().unwrap();
@@ -399,6 +780,10 @@ impl Game {
             );
         }
 
+        if self.fields.is_some() {
+            self.render_chat();
+        }
+
         self.drawing_context.stroke();
     }
 
@@ -411,22 +796,52 @@ impl Game {
     }
 
     fn join_game(&mut self, game_id: GameId, socket: WebSocket) {
-        if let Some(player_id) = self.url_param("player") {
-            let request = self.client.rejoin_game(player_id);
-            self.send_request(request, &socket);
-            self.state = GameState::WaitingForGameJoin(socket);
-        } else {
-            self.client.join_game(game_id);
-            self.add_player(socket);
-        }
+        self.client.join_game(game_id);
+        self.add_player(socket);
+    }
+
+    fn rejoin_game(&mut self, token: PlayerToken, socket: WebSocket) {
+        let request = self.client.rejoin_game(token);
+        self.send_request(request, &socket);
+        self.state = GameState::WaitingForGameJoin(socket);
+    }
+
+    fn list_open_games(&mut self, socket: WebSocket) {
+        let request = self.client.list_open_games();
+        self.send_request(request, &socket);
+        self.state = GameState::WaitingForOpenGames(socket);
+    }
+
+    fn quick_match(&mut self, socket: WebSocket) {
+        let request = self.client.quick_match(&self.player_name());
+        self.send_request(request, &socket);
+        self.state = GameState::WaitingForPlayerAdd(socket);
     }
 
-    fn create_game(&mut self, socket: WebSocket) {
-        let request = self.client.create_game();
+    fn create_game(&mut self, options: GameOptions, socket: WebSocket) {
+        let request = self.client.create_custom_game(options);
         self.send_request(request, &socket);
         self.state = GameState::WaitingForGameCreate(socket);
     }
 
+    fn rematch(&mut self, socket: WebSocket) {
+        let request = self.client.rematch();
+        if !self.send_player_request(request, &socket) {
+            self.state = GameState::GameOver(socket);
+            return;
+        }
+        self.message(
+            "Waiting for enemy to accept the rematch",
+            MessageLevel::Info,
+        );
+        self.state = GameState::WaitingForRematch(socket);
+    }
+
+    fn join_with_code(&mut self, join_code: JoinCode, socket: WebSocket) {
+        self.client.join_with_code(join_code);
+        self.add_player(socket);
+    }
+
     fn url_param<R: std::str::FromStr>(&self, param: &str) -> Option<R> {
         let search = window().location().search().unwrap();
         let params = UrlSearchParams::new_with_str(&search).unwrap();
@@ -441,12 +856,20 @@ impl Game {
         console_log!("established connection to server");
         let socket = socket.clone();
 
-        if let Some(game_id) = self.url_param("game") {
+        let request = self.client.hello();
+        self.send_request(request, &socket);
+        self.state = GameState::WaitingForHello(socket);
+    }
+
+    fn on_hello(&mut self, socket: WebSocket) {
+        if let Some(token) = self.url_param("player") {
+            self.rejoin_game(token, socket);
+        } else if let Some(join_code) = self.url_param("code") {
+            self.join_with_code(join_code, socket);
+        } else if let Some(game_id) = self.url_param("game") {
             self.join_game(game_id, socket);
-        } else if let Some(player_id) = self.url_param::<PlayerId>("player") {
-            self.join_game(player_id.game_id(), socket);
         } else {
-            self.create_game(socket);
+            self.list_open_games(socket);
         }
     }
 
@@ -455,30 +878,51 @@ impl Game {
     }
 
     fn on_mouse_click(&mut self, x: u32, y: u32) {
+        if self.fields.is_some() {
+            if let Some(emote) = emote_at(x, y) {
+                self.send_chat(ChatMessage::Emote(emote));
+                return;
+            }
+        }
         match self.state.take() {
             GameState::MyTurn(socket) => {
                 let field = &self.fields.as_ref().unwrap().speculative_field;
                 if let Some(location) = field.location(x, y) {
-                    let player_id = self.client.player_id();
                     let other_player_id = self.client.other_player_ids()[0];
 
-                    let request = self.client.advance(player_id, other_player_id, location);
-                    self.send_request(request, &socket);
-
-                    self.state = GameState::WaitingForAttackResult(socket);
+                    let request = self.client.advance(other_player_id, location);
+                    self.state = if self.send_player_request(request, &socket) {
+                        GameState::WaitingForAttackResult(socket)
+                    } else {
+                        GameState::MyTurn(socket)
+                    };
                 } else {
                     self.state = GameState::MyTurn(socket);
                 }
             }
+            GameState::Lobby(open_games, socket) => {
+                let choice = lobby_row(y)
+                    .and_then(|row| lobby_choices(&open_games).into_iter().nth(row))
+                    .map(|(_, choice)| choice);
+                match choice {
+                    Some(LobbyChoice::New(options)) => self.create_game(options, socket),
+                    Some(LobbyChoice::QuickMatch) => self.quick_match(socket),
+                    Some(LobbyChoice::Refresh) => self.list_open_games(socket),
+                    Some(LobbyChoice::Join(game_id)) => self.join_game(game_id, socket),
+                    None => self.state = GameState::Lobby(open_games, socket),
+                }
+            }
+            // The game over message doubles as the rematch button.
+            GameState::GameOver(socket) if (y as f64) < LOBBY_TOP => self.rematch(socket),
             GameState::PlacingShip(ship_id, direction, socket) => {
                 let field = &self.fields.as_ref().unwrap().own_field;
                 if let Some(location) = field.location(x, y) {
-                    let player_id = self.client.player_id();
-                    let request = self
-                        .client
-                        .place_ship(player_id, ship_id, location, direction);
-                    self.send_request(request, &socket);
-                    self.try_to_place_ship(socket);
+                    let request = self.client.place_ship(ship_id, location, direction);
+                    if self.send_player_request(request, &socket) {
+                        self.try_to_place_ship(socket);
+                    } else {
+                        self.state = GameState::PlacingShip(ship_id, direction, socket);
+                    }
                 } else {
                     self.state = GameState::PlacingShip(ship_id, direction, socket);
                 }
@@ -490,9 +934,9 @@ impl Game {
     }
 }
 
-fn connect_websocket(game: Rc<RefCell<Game>>, host: &str) -> Result<(), JsValue> {
+fn connect_websocket(game: Rc<RefCell<Game>>, url: &str) -> Result<(), JsValue> {
     // connect to the server
-    let ws = WebSocket::new(&format!("ws://{}:9090", host))?;
+    let ws = WebSocket::new(url)?;
     ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
 
     // when we get a message, forward it to the game
@@ -504,9 +948,8 @@ fn connect_websocket(game: Rc<RefCell<Game>>, host: &str) -> Result<(), JsValue>
             buffer.extend(&array.to_vec());
             let mut game = cloned_game.borrow_mut();
 
-            let mut reader = buffer.as_slice();
-            if game.on_data(&mut reader) {
-                buffer = reader.to_vec();
+            if let Err(e) = game.on_data(&mut buffer) {
+                game.message(format!("Connection error: {}", e), MessageLevel::Error);
             }
         } else {
             let mut game = cloned_game.borrow_mut();
@@ -599,18 +1042,27 @@ fn set_up_input(game: Rc<RefCell<Game>>) {
         .add_event_listener_with_callback("mouseup", closure.as_ref().unchecked_ref())
         .unwrap();
     closure.forget();
-}
 
-/// Get the host, minus the port
-fn url_host_name() -> String {
+    let closure = Closure::wrap(Box::new(move |event: web_sys::KeyboardEvent| {
+        game.borrow_mut().on_key(&event.key());
+    }) as Box<dyn FnMut(_)>);
+
     window()
-        .location()
-        .host()
-        .unwrap()
-        .split(":")
-        .next()
+        .document()
         .unwrap()
-        .to_owned()
+        .add_event_listener_with_callback("keydown", closure.as_ref().unchecked_ref())
+        .unwrap();
+    closure.forget();
+}
+
+/// The game's websocket, on the server the page came from.
+fn socket_url() -> String {
+    let location = window().location();
+    let scheme = match location.protocol().unwrap().as_str() {
+        "https:" => "wss",
+        _ => "ws",
+    };
+    format!("{}://{}/ws", scheme, location.host().unwrap())
 }
 
 #[wasm_bindgen(start)]
@@ -619,7 +1071,7 @@ pub fn start() -> Result<(), JsValue> {
     console_log!("Battleship Client Starting");
 
     let canvas = canvas();
-    let canvas_width = 1224;
+    let canvas_width = 1524;
     let canvas_height = 590;
     canvas.set_width(canvas_width);
     canvas.set_height(canvas_height);
@@ -631,7 +1083,7 @@ pub fn start() -> Result<(), JsValue> {
         canvas_height,
     )));
 
-    connect_websocket(game.clone(), &url_host_name())?;
+    connect_websocket(game.clone(), &socket_url())?;
     set_up_rendering(game.clone());
     set_up_input(game.clone());
 
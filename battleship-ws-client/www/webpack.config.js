@@ -11,4 +11,13 @@ module.exports = {
   plugins: [
     new CopyWebpackPlugin(['index.html'])
   ],
+  // During development, reach the game on a battleship-ws-server listening on port 9090.
+  devServer: {
+    proxy: {
+      "/ws": {
+        target: "ws://localhost:9090",
+        ws: true,
+      },
+    },
+  },
 };
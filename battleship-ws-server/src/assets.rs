@@ -0,0 +1,133 @@
+// Copyright 2020 Remi Bernotavicius
+
+//! Serves the web client's files over plain HTTP, on the same port as the game.
+
+use std::io::{self, Write as _};
+use std::net::TcpStream;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How many requests are answered at once. Any more are turned away until one of them finishes.
+const MAX_REQUESTS: usize = 16;
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "application/javascript",
+        Some("wasm") => "application/wasm",
+        Some("css") => "text/css",
+        Some("json") => "application/json",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The file in `directory` that `uri` names, unless it would be outside of it.
+fn file_path(directory: &Path, uri: &str) -> Option<PathBuf> {
+    let path = uri.split(&['?', '#'][..]).next()?;
+    let relative = Path::new(path.trim_start_matches('/'));
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+    let mut path = directory.join(relative);
+    if path.is_dir() {
+        path.push("index.html");
+    }
+    Some(path)
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+    head_only: bool,
+) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    if !head_only {
+        stream.write_all(body)?;
+    }
+    stream.flush()
+}
+
+/// The files of the web client, if there is a directory of them.
+pub struct Assets {
+    directory: Option<PathBuf>,
+    /// How many requests are being answered right now.
+    serving: AtomicUsize,
+}
+
+impl Assets {
+    pub fn new(directory: Option<PathBuf>) -> Self {
+        Self {
+            directory,
+            serving: AtomicUsize::new(0),
+        }
+    }
+
+    /// Answer a request for one of the files, then close the connection. Unless there are already
+    /// `MAX_REQUESTS` being answered, in which case the client is told to try again later.
+    pub fn serve(&self, mut stream: TcpStream, method: &str, uri: &str) -> io::Result<()> {
+        if self.serving.fetch_add(1, Ordering::SeqCst) >= MAX_REQUESTS {
+            self.serving.fetch_sub(1, Ordering::SeqCst);
+            let body = b"busy, try again later\n";
+            return respond(
+                &mut stream,
+                "503 Service Unavailable",
+                "text/plain",
+                body,
+                false,
+            );
+        }
+        let result = serve(self.directory.as_deref(), stream, method, uri);
+        self.serving.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+}
+
+/// Answer a request for one of the files in `directory`, then close the connection. Without a
+/// directory every file is missing.
+fn serve(
+    directory: Option<&Path>,
+    mut stream: TcpStream,
+    method: &str,
+    uri: &str,
+) -> io::Result<()> {
+    let head_only = match method {
+        "GET" => false,
+        "HEAD" => true,
+        _ => {
+            let body = b"method not allowed\n";
+            return respond(
+                &mut stream,
+                "405 Method Not Allowed",
+                "text/plain",
+                body,
+                false,
+            );
+        }
+    };
+    let path = directory.and_then(|directory| file_path(directory, uri));
+    match path.map(|path| (std::fs::read(&path), path)) {
+        Some((Ok(body), path)) => {
+            respond(&mut stream, "200 OK", content_type(&path), &body, head_only)
+        }
+        _ => respond(
+            &mut stream,
+            "404 Not Found",
+            "text/plain",
+            b"not found\n",
+            head_only,
+        ),
+    }
+}
@@ -1,11 +1,20 @@
 // Copyright 2020 Remi Bernotavicius
 
-use battleship_game::server::blocking::{BlockingGameServer, Error as ServerError, Listener};
+use assets::Assets;
+use battleship_game::server::{
+    blocking::{BlockingGameServer, Close, Error as ServerError, Listener, SplitStream},
+    storage::FileStorage,
+    GameServer, WRITE_TIMEOUT,
+};
 use log::info;
-use std::{io, net, sync::Mutex};
+use std::io::Read as _;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use std::{io, net};
 use websocket::{
-    server::{sync::AcceptResult, NoTlsAcceptor, WsServer},
-    Message, OwnedMessage,
+    dataframe::Opcode, server::upgrade::sync::IntoWs as _, ws::util::header::read_header, Message,
 };
 
 #[derive(Debug)]
@@ -28,87 +37,269 @@ impl From<ServerError> for Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
-struct WsListener(Mutex<WsServer<NoTlsAcceptor, net::TcpListener>>);
+mod assets;
+
+/// Where on the server the game's websocket is. Everything else is the web client's files.
+const SOCKET_PATH: &str = "/ws";
+
+/// The longest payload a websocket ping or pong may have.
+const MAX_CONTROL_PAYLOAD: u64 = 125;
+
+/// How long a new connection has to send its HTTP request.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many new connections may be sending their HTTP request, or be answered, at once. Any more
+/// are hung up on as soon as they are accepted.
+const MAX_HANDSHAKES: usize = 64;
+
+struct WsListener {
+    listener: net::TcpListener,
+    /// The built web client, if it is served from here.
+    assets: Arc<Assets>,
+    /// How many connections are handshaking right now.
+    handshakes: Arc<AtomicUsize>,
+}
 
 impl WsListener {
-    fn bind<A: net::ToSocketAddrs>(addr: A) -> io::Result<Self> {
-        let s = WsServer::<NoTlsAcceptor, net::TcpListener>::bind(addr)
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, ""))?;
-        Ok(Self(Mutex::new(s)))
+    fn bind<A: net::ToSocketAddrs>(addr: A, assets: Option<PathBuf>) -> io::Result<Self> {
+        Ok(Self {
+            listener: net::TcpListener::bind(addr)?,
+            assets: Arc::new(Assets::new(assets)),
+            handshakes: Arc::new(AtomicUsize::new(0)),
+        })
     }
 
     fn local_addr(&self) -> io::Result<net::SocketAddr> {
-        self.0.lock().unwrap().local_addr()
+        self.listener.local_addr()
     }
+}
+
+/// Counts a connection among those handshaking, until it is dropped.
+struct Handshake(Arc<AtomicUsize>);
 
-    fn accept(&self) -> AcceptResult<net::TcpStream> {
-        self.0.lock().unwrap().accept()
+impl Handshake {
+    /// Returns `None` if there are already `MAX_HANDSHAKES` under way.
+    fn start(handshakes: &Arc<AtomicUsize>) -> Option<Self> {
+        if handshakes.fetch_add(1, Ordering::SeqCst) >= MAX_HANDSHAKES {
+            handshakes.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Self(handshakes.clone()))
     }
 }
 
+impl Drop for Handshake {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A new connection, which becomes a websocket once it is split on its own thread.
 struct WsStream {
-    stream: websocket::client::sync::Client<net::TcpStream>,
-    buffer: Vec<u8>,
+    stream: net::TcpStream,
+    assets: Arc<Assets>,
+    /// Held until the handshake is over.
+    handshake: Handshake,
 }
 
-impl io::Read for WsStream {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if self.buffer.len() < buf.len() {
-            let message = self
-                .stream
-                .recv_message()
-                .map_err(|_| io::Error::new(io::ErrorKind::Other, ""))?;
-            match message {
-                OwnedMessage::Binary(d) => self.buffer.extend(d),
-                OwnedMessage::Text(d) => self.buffer.extend(d.bytes()),
-                _ => (),
+impl WsStream {
+    /// Read the HTTP request the connection starts with, and upgrade it if it's for the game's
+    /// websocket. Anything else is answered with one of the web client's files, and fails so
+    /// nothing more is done with the connection.
+    fn handshake(self) -> io::Result<websocket::client::sync::Client<net::TcpStream>> {
+        let Self {
+            stream,
+            assets,
+            handshake: _handshake,
+        } = self;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        match stream.into_ws() {
+            Ok(upgrade) if upgrade.uri() == SOCKET_PATH => {
+                let client = upgrade.accept().map_err(|(_, e)| e)?;
+                client.stream_ref().set_read_timeout(None)?;
+                Ok(client)
             }
+            Ok(upgrade) => {
+                let uri = upgrade.uri();
+                upgrade.reject().ok();
+                Err(io::Error::other(format!("rejected websocket at {}", uri)))
+            }
+            Err((stream, Some(request), _, _)) => {
+                let (method, uri) = (request.subject.0.to_string(), request.subject.1.to_string());
+                info!("{} {}", method, uri);
+                if let Err(e) = assets.serve(stream, &method, &uri) {
+                    info!("failed to serve {}: {}", uri, e);
+                }
+                Err(io::Error::other(format!(
+                    "{} {} is not a websocket",
+                    method, uri
+                )))
+            }
+            Err((_, None, _, e)) => Err(invalid_data(e)),
         }
+    }
+}
+
+impl SplitStream for WsStream {
+    type Reader = WsReader;
+    type Writer = WsWriter;
+
+    fn split(self) -> io::Result<(WsReader, WsWriter)> {
+        let (reader, writer) = self.handshake()?.split()?;
+        let sender = Arc::new(Mutex::new(writer));
+        Ok((
+            WsReader {
+                reader,
+                sender: sender.clone(),
+                mask: [0; 4],
+                position: 0,
+                remaining: 0,
+            },
+            WsWriter(sender),
+        ))
+    }
+}
+
+/// Reads the payloads of the websocket frames as one stream, straight from the socket. Nothing is
+/// buffered however large a frame claims to be, so the messages within are limited like they are
+/// on any other stream.
+struct WsReader {
+    reader: websocket::receiver::Reader<net::TcpStream>,
+    /// Shared with the `WsWriter`, so pings can be answered without splitting its frames.
+    sender: Sender,
+    /// The current frame's masking key, how far into its payload we are and how much is left.
+    mask: [u8; 4],
+    position: usize,
+    remaining: u64,
+}
 
-        let read = self.buffer.as_slice().read(buf)?;
-        self.buffer = self.buffer.split_off(read);
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl WsReader {
+    /// Skip ahead to the next data frame with a payload. Returns false if the connection is closed.
+    fn next_frame(&mut self) -> io::Result<bool> {
+        while self.remaining == 0 {
+            let header = read_header(&mut self.reader.stream).map_err(invalid_data)?;
+            let mask = header
+                .mask
+                .ok_or_else(|| invalid_data("client frames must be masked"))?;
+            match Opcode::new(header.opcode) {
+                Some(Opcode::Continuation | Opcode::Text | Opcode::Binary) => {
+                    self.mask = mask;
+                    self.position = 0;
+                    self.remaining = header.len;
+                }
+                Some(Opcode::Close) => return Ok(false),
+                Some(Opcode::Ping) => {
+                    let payload = self.control_payload(header.len, mask)?;
+                    send(&self.sender, &Message::pong(payload))?;
+                }
+                Some(Opcode::Pong) => {
+                    self.control_payload(header.len, mask)?;
+                }
+                _ => return Err(invalid_data("unknown websocket opcode")),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Read a ping or pong's payload, which is short enough to keep.
+    fn control_payload(&mut self, len: u64, mask: [u8; 4]) -> io::Result<Vec<u8>> {
+        if len > MAX_CONTROL_PAYLOAD {
+            return Err(invalid_data("control frame too long"));
+        }
+        let mut payload = vec![0; len as usize];
+        self.reader.stream.read_exact(&mut payload)?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(payload)
+    }
+}
+
+impl io::Read for WsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || !self.next_frame()? {
+            return Ok(0);
+        }
+        let len = (buf.len() as u64).min(self.remaining) as usize;
+        let read = self.reader.stream.read(&mut buf[..len])?;
+        for byte in &mut buf[..read] {
+            *byte ^= self.mask[self.position % 4];
+            self.position += 1;
+        }
+        self.remaining -= read as u64;
         Ok(read)
     }
 }
 
-impl io::Write for WsStream {
+type Sender = Arc<Mutex<websocket::sender::Writer<net::TcpStream>>>;
+
+fn send(sender: &Sender, message: &Message) -> io::Result<()> {
+    sender
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .send_message(message)
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, ""))
+}
+
+struct WsWriter(Sender);
+
+impl io::Write for WsWriter {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.stream
-            .send_message(&Message::binary(buf))
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, ""))?;
+        send(&self.0, &Message::binary(buf))?;
         Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.stream.writer_mut().flush()
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .stream
+            .flush()
     }
 }
 
-struct WsIncoming<'a> {
-    listener: &'a WsListener,
+impl Close for WsWriter {
+    fn close(&self) -> io::Result<()> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .stream
+            .shutdown(net::Shutdown::Both)
+    }
 }
 
-impl<'a> WsIncoming<'a> {
-    fn accept(&mut self) -> io::Result<WsStream> {
-        let upgrade = self
-            .listener
-            .accept()
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, ""))?;
-        let client = upgrade
-            .accept()
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, ""))?;
-        Ok(WsStream {
-            stream: client,
-            buffer: vec![],
-        })
-    }
+/// Accepts connections without reading anything from them, so a slow client can't hold up
+/// anyone else connecting. Connections beyond `MAX_HANDSHAKES` are closed straight away rather
+/// than each getting a thread to wait on them.
+struct WsIncoming<'a> {
+    listener: &'a WsListener,
 }
 
 impl<'a> Iterator for WsIncoming<'a> {
     type Item = io::Result<WsStream>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        Some(self.accept())
+        loop {
+            let stream = match self.listener.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) => return Some(Err(e)),
+            };
+            match Handshake::start(&self.listener.handshakes) {
+                Some(handshake) => {
+                    return Some(Ok(WsStream {
+                        stream,
+                        assets: self.listener.assets.clone(),
+                        handshake,
+                    }))
+                }
+                None => info!("turning away a connection, too many are handshaking"),
+            }
+        }
     }
 }
 
@@ -121,15 +312,47 @@ impl<'a> Listener<'a> for WsListener {
     }
 }
 
+/// Takes the address to listen on, and optionally a directory to keep games in so they survive a
+/// restart. With `--tcp ADDRESS`, it also serves the same games to terminal players over TCP. With
+/// `--assets DIRECTORY`, it serves the built web client from that directory, with the game at
+/// `/ws`.
 fn main() -> Result<()> {
-    let arg = std::env::args().skip(1).next();
+    let mut address = None;
+    let mut data_directory = None;
+    let mut tcp_address = None;
+    let mut assets = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tcp" => tcp_address = Some(args.next().expect("--tcp needs an address")),
+            "--assets" => assets = Some(args.next().expect("--assets needs a directory").into()),
+            _ if address.is_none() => address = Some(arg),
+            _ => data_directory = Some(arg),
+        }
+    }
 
     simple_logger::init_with_level(log::Level::Info).unwrap();
 
-    let listener = WsListener::bind(&arg.unwrap_or("0.0.0.0:0".into()))?;
-    info!("listening on {}", listener.local_addr()?);
+    let game = match data_directory {
+        Some(directory) => GameServer::with_storage(Box::new(FileStorage::open(directory)?))?,
+        None => GameServer::new(),
+    };
 
-    let mut game_server = BlockingGameServer::new();
-    game_server.run(&listener);
+    let listener = WsListener::bind(&address.unwrap_or("0.0.0.0:0".into()), assets)?;
+    info!(
+        "listening on ws://{}{}",
+        listener.local_addr()?,
+        SOCKET_PATH
+    );
+
+    let mut game_server = BlockingGameServer::with_game_server(game);
+    match tcp_address {
+        Some(tcp_address) => {
+            let tcp_listener = net::TcpListener::bind(tcp_address)?;
+            info!("listening on tcp://{}", tcp_listener.local_addr()?);
+            game_server.run_both(&listener, &tcp_listener);
+        }
+        None => game_server.run(&listener),
+    }
     Ok(())
 }